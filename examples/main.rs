@@ -13,23 +13,15 @@ async fn main() -> Result<()> {
     let args = Args::parse();
 
     let manager = Manager::new().await?;
-    let adapter = manager.adapters().await?.drain(..).next().unwrap();
+    let adapter = SpikePrime::adapter_by_index(&manager, 0).await?;
     println!("Scanning for SPIKE Prime hubs");
     let device = SpikePrime::scan_first(&adapter).await?;
     println!("Device found!");
     let mut connection = device.connect().await?;
     println!("Connected!");
-    let code = fs::read_to_string(args.file)?.replace("\n", "\r\n");
+    let code = fs::read_to_string(args.file)?;
     println!("Uploading file...");
-    //connection.enable_device_notifications().await?;
-    println!("Device notifications enabled...");
-    let _ = connection.clear_program_slot(0).await; // Ignore NACK
-    println!("Program slot cleared!");
-    connection
-        .upload_program(0, "program.py".to_string(), code)
-        .await?;
-    println!("Uploaded!");
-    connection.start_program(0).await?;
+    connection.deploy_and_run(0, "program.py", &code).await?;
     println!("Program started");
 
     loop {
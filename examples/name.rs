@@ -5,7 +5,7 @@ use spike_prime::prelude::*;
 #[tokio::main]
 async fn main() -> Result<()> {
     let manager = Manager::new().await?;
-    let adapter = manager.adapters().await?.drain(..).next().unwrap();
+    let adapter = SpikePrime::adapter_by_index(&manager, 0).await?;
     println!("Scanning for SPIKE Prime hubs");
     let device = SpikePrime::scan_first(&adapter).await?;
     println!("Device found!");
@@ -0,0 +1,119 @@
+//! Module for using a SPIKE Prime from synchronous code, without managing an async runtime. Requires the `blocking` feature.
+
+use std::time::Duration;
+
+use btleplug::platform::{Adapter, Manager};
+use tokio::runtime::Runtime;
+
+use crate::{
+    SpikePrime,
+    connection::{SpikeConnection, UploadReport, message::*},
+    error::*,
+};
+
+/// A blocking version of [`SpikeConnection`], which runs it on its own background runtime.
+///
+/// Only the most common methods are mirrored here. For anything else, use [`BlockingSpikeConnection::block_on`] with the async [`SpikeConnection`].
+/// This can't be used from inside an async runtime, since blocking would stall it. Dropping it disconnects from the hub.
+#[derive(Debug)]
+pub struct BlockingSpikeConnection {
+    connection: SpikeConnection,
+    // Dropped last, since the connection's background task runs on it
+    runtime: Runtime,
+}
+
+impl BlockingSpikeConnection {
+    /// Connects to the first SPIKE Prime found with the first Bluetooth adapter, see [`SpikePrime::scan_first`].
+    pub fn connect_first() -> Result<Self> {
+        Self::connect_with(async |adapter| SpikePrime::scan_first(adapter).await)
+    }
+
+    /// Connects to the first SPIKE Prime called `name` found with the first Bluetooth adapter, see [`SpikePrime::scan_first_by_name`].
+    pub fn connect_by_name(name: &str) -> Result<Self> {
+        Self::connect_with(async |adapter| SpikePrime::scan_first_by_name(adapter, name).await)
+    }
+
+    fn connect_with(scan: impl AsyncFnOnce(&Adapter) -> Result<SpikePrime>) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()?;
+        let connection = runtime.block_on(async {
+            let manager = Manager::new().await?;
+            let adapter = SpikePrime::adapter_by_index(&manager, 0).await?;
+            scan(&adapter).await?.connect().await
+        })?;
+        Ok(Self {
+            connection,
+            runtime,
+        })
+    }
+
+    /// Runs a future to completion on the connection's runtime, for using the parts of [`SpikeConnection`] that aren't mirrored here.
+    pub fn block_on<T>(&mut self, f: impl AsyncFnOnce(&mut SpikeConnection) -> T) -> T {
+        self.runtime.block_on(f(&mut self.connection))
+    }
+
+    /// Returns the async connection, like for reading its [`SpikeConnection::info`].
+    pub fn connection(&self) -> &SpikeConnection {
+        &self.connection
+    }
+
+    /// See [`SpikeConnection::upload_program`].
+    pub fn upload_program<S>(&mut self, slot: S, name: String, code: String) -> Result<UploadReport>
+    where
+        S: TryInto<ProgramSlot>,
+        Error: From<S::Error>,
+    {
+        self.runtime
+            .block_on(self.connection.upload_program(slot, name, code))
+    }
+
+    /// See [`SpikeConnection::start_program`].
+    pub fn start_program<S>(&mut self, slot: S) -> Result<()>
+    where
+        S: TryInto<ProgramSlot>,
+        Error: From<S::Error>,
+    {
+        self.runtime.block_on(self.connection.start_program(slot))
+    }
+
+    /// See [`SpikeConnection::stop_program`].
+    pub fn stop_program<S>(&mut self, slot: S) -> Result<()>
+    where
+        S: TryInto<ProgramSlot>,
+        Error: From<S::Error>,
+    {
+        self.runtime.block_on(self.connection.stop_program(slot))
+    }
+
+    /// See [`SpikeConnection::get_hub_name`].
+    pub fn get_hub_name(&mut self) -> Result<String> {
+        self.runtime.block_on(self.connection.get_hub_name())
+    }
+
+    /// See [`SpikeConnection::set_hub_name`].
+    pub fn set_hub_name(&mut self, name: &str) -> Result<()> {
+        self.runtime.block_on(self.connection.set_hub_name(name))
+    }
+
+    /// See [`SpikeConnection::try_receive_message`]. This doesn't block, so it can be called every frame of a GUI.
+    pub fn try_receive_message(&mut self) -> Option<Result<TxMessage>> {
+        self.connection.try_receive_message()
+    }
+
+    /// See [`SpikeConnection::receive_message_timeout`].
+    pub fn receive_message_timeout(&mut self, timeout: Duration) -> Result<TxMessage> {
+        self.runtime
+            .block_on(self.connection.receive_message_timeout(timeout))
+    }
+}
+
+impl Drop for BlockingSpikeConnection {
+    fn drop(&mut self) {
+        // The connection can't disconnect itself when dropped, since there's no runtime outside of this one
+        if tokio::runtime::Handle::try_current().is_err() {
+            let _ = self.runtime.block_on(self.connection.close());
+        }
+    }
+}
@@ -1,162 +1,718 @@
-use std::{pin::Pin, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt, io,
+    path::Path,
+    sync::{
+        Arc, PoisonError,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    task::Poll,
+    time::{Duration, Instant},
+};
 
-use crate::{connection::message::*, error::*};
+use crate::{
+    connection::{message::*, transport::*},
+    error::*,
+};
 use btleplug::{
-    api::{Characteristic, Peripheral as _, ValueNotification, WriteType},
-    platform::Peripheral,
+    api::WriteType,
+    platform::{Peripheral, PeripheralId},
 };
 use futures::{Stream, StreamExt};
 use tokio::{
     sync::{
-        Mutex,
-        mpsc::{self, Receiver, Sender},
+        Mutex, Notify,
+        mpsc::{
+            self, Receiver, Sender,
+            error::{TryRecvError, TrySendError},
+        },
     },
     task::JoinHandle,
 };
 use uuid::Uuid;
 
-const DEVICE_NOTIFICATION_INTERVAL: u16 = 10;
+/// Re-exported for [`SpikeConnection::upload_program_cancellable`], so it can be used without depending on `tokio-util`.
+pub use tokio_util::sync::CancellationToken;
 
+const DEVICE_NOTIFICATION_INTERVAL: u16 = 10;
+const CHUNK_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+/// How far the battery has to rise back above a threshold before it can be crossed again. This keeps a reading jittering around a threshold from sending lots of events.
+const BATTERY_HYSTERESIS: u8 = 2;
+/// How many messages are set aside while waiting for a response before giving up.
+const MAX_PENDING_MESSAGES: usize = 16;
+/// How long [`SpikeConnection::request`] waits for a response.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many times bigger than the max message size a frame can get before it's assumed its delimiter was lost.
+const MAX_FRAME_SIZE_FACTOR: usize = 4;
+/// How many raw frames are kept for [`SpikeConnection::receive_raw`] before new ones are dropped.
+const RAW_FRAME_CAPACITY: usize = 16;
+/// How many device notifications [`SpikeConnection::events`] buffers.
+const EVENT_CAPACITY: usize = 16;
+
+pub mod cobs;
 pub mod message;
+pub mod transport;
 
 /// Struct that represents the connection between a SPIKE Prime and the devices connected to it.
+///
+/// Dropping the connection disconnects from the hub in the background, which needs a tokio runtime. If it's dropped outside of one, the hub is left connected until the program exits, so use [`SpikeConnection::disconnect`] instead.
 pub struct SpikeConnection {
-    connection: Peripheral,
-    /// RX (from the hub's perspective)
-    rx: Characteristic,
-    rpc_version: (u8, u8, u16),
-    firmware_version: (u8, u8, u16),
-    max_packet_size: u16,
-    max_message_size: u16,
-    max_chunk_size: u16,
+    transport: Box<dyn Transport>,
+    auto_reconnect: bool,
+    reliable_writes: bool,
+    chunk_ack_timeout: Duration,
+    crc_algorithm: &'static crc::Algorithm<u32>,
+    info: InfoResponse,
     device_notification: Arc<Mutex<Option<DeviceNotification>>>,
+    device_notification_interval: Option<u16>,
+    device_notification_tx: Arc<Mutex<Option<Sender<DeviceNotification>>>>,
     msg_rx: Receiver<Result<TxMessage>>,
     console_rx: Receiver<ConsoleNotification>,
     program_flow_rx: Receiver<ProgramFlowNotification>,
-    _msg_handle: JoinHandle<()>,
+    program_event_tx: Arc<Mutex<Option<Sender<ProgramEvent>>>>,
+    error_rx: Receiver<Error>,
+    lifecycle_rx: Receiver<LifecycleEvent>,
+    battery_thresholds: Arc<Mutex<BatteryThresholds>>,
+    notification_activity: Arc<Notify>,
+    /// The name and CRC of the last program uploaded to each slot over this connection.
+    /// Behind a lock so it can be cleared when relinking, which only has `&self`.
+    uploaded_crcs: std::sync::Mutex<HashMap<u8, (String, u32)>>,
+    exchange_log: Mutex<Option<ExchangeLog>>,
+    /// The slot of the last program started over this connection. See [`SpikeConnection::stop_all_programs`].
+    last_started: Option<ProgramSlot>,
+    /// Messages set aside while waiting for a response. See [`SpikeConnection::receive_response`].
+    pending: VecDeque<TxMessage>,
+    /// The most bytes to buffer while waiting for a frame to end. Shared with [`filter_thread`].
+    max_frame_size: Arc<AtomicUsize>,
+    /// Called with the raw bytes going to and from the hub. Shared with [`filter_thread`].
+    trace: Arc<std::sync::Mutex<Option<TraceFn>>>,
+    /// Where to send decoded frames, once [`SpikeConnection::receive_raw`] has been called.
+    raw_tx: Arc<Mutex<Option<Sender<Vec<u8>>>>>,
+    raw_rx: Option<Receiver<Vec<u8>>>,
+    /// Whether messages of unknown types are dropped instead of being reported. Shared with [`filter_thread`].
+    ignore_unknown: Arc<AtomicBool>,
+    /// How many messages of unknown types were received. Shared with [`filter_thread`].
+    unknown_messages: Arc<AtomicUsize>,
+    /// How many console and program flow notifications were dropped because their buffers were full. Shared with [`filter_thread`].
+    dropped_console: Arc<AtomicUsize>,
+    dropped_program_flow: Arc<AtomicUsize>,
+    /// Whether the hub is still connected, as far as the connection can tell. Shared with [`filter_thread`].
+    connected: Arc<AtomicBool>,
+    config: SpikeConnectionConfig,
+    throughput_callback: Option<Box<dyn FnMut(f64) + Send + Sync>>,
+    msg_handle: JoinHandle<()>,
+    /// The receivers of the [`filter_thread`] started after relinking, which only has `&self`. They replace the old ones the next time something is received.
+    relinked: std::sync::Mutex<Option<FilterReceivers>>,
 }
 
-impl std::fmt::Debug for SpikeConnection {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("SpikeConnection")
-            .field("rpc_version", &self.rpc_version)
-            .field("firmware_version", &self.firmware_version)
-            .field("max_packet_size", &self.max_packet_size)
-            .field("max_message_size", &self.max_message_size)
-            .field("max_chunk_size", &self.max_chunk_size)
-            .finish()
+/// Settings for a [`SpikeConnection`], which are used when it's connected. See [`SpikePrime::connect_with_config`](crate::SpikePrime::connect_with_config).
+///
+/// Messages from the hub are sorted into buffers, one for each kind, until they're received. If the buffer for [`SpikeConnection::receive_message`] fills up, nothing else from the hub is received until it's read from.
+/// Console and program flow notifications are dropped instead once their buffers are full, so they can't hold up responses to requests. Make their buffers big enough for the most that could pile up,
+/// like the console output of a program that prints a lot, to keep them from being dropped. See [`SpikeConnection::dropped_console_count`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SpikeConnectionConfig {
+    /// How many messages [`SpikeConnection::receive_message`] buffers. Defaults to 4.
+    pub message_capacity: usize,
+    /// How many console notifications [`SpikeConnection::console_notification`] buffers before dropping them. Defaults to 64.
+    pub console_capacity: usize,
+    /// How many program flow notifications [`SpikeConnection::program_flow_notification`] buffers before dropping them. Defaults to 4.
+    pub program_flow_capacity: usize,
+    /// How many errors [`SpikeConnection::decode_error`] buffers. Newer errors are dropped once it's full, instead of waiting. Defaults to 4.
+    pub error_capacity: usize,
+}
+
+impl Default for SpikeConnectionConfig {
+    fn default() -> Self {
+        Self {
+            message_capacity: 4,
+            console_capacity: 64,
+            program_flow_capacity: 4,
+            error_capacity: 4,
+        }
     }
 }
 
-impl SpikeConnection {
-    pub(crate) async fn new(connection: Peripheral) -> Result<Self> {
-        const RX_UUID: Uuid = Uuid::from_bytes([
-            0x00, 0x00, 0xFD, 0x02, 0x00, 0x01, 0x10, 0x00, 0x80, 0x00, 0x00, 0x80, 0x5F, 0x9B,
-            0x34, 0xFB,
-        ]);
-        const TX_UUID: Uuid = Uuid::from_bytes([
-            0x00, 0x00, 0xFD, 0x02, 0x00, 0x02, 0x10, 0x00, 0x80, 0x00, 0x00, 0x80, 0x5F, 0x9B,
-            0x34, 0xFB,
-        ]);
-
-        connection.connect().await?;
-        connection.discover_services().await?;
-        let mut rx = None;
-        let mut tx = None;
-        for i in connection.characteristics() {
-            match i.uuid {
-                RX_UUID => {
-                    rx = Some(i);
+/// A freshly established connection to a hub. See [`SpikeConnection::establish`].
+struct Link {
+    info: InfoResponse,
+    notifications: ByteStream,
+}
+
+/// Statistics about a transfer done with [`SpikeConnection::send_chunks`].
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct UploadReport {
+    /// The number of bytes transferred, not including message headers
+    pub bytes: usize,
+    /// The number of chunks the data was split into
+    pub chunks: usize,
+    /// How long the transfer took
+    pub elapsed: Duration,
+    /// The CRC-32 of the data, as the hub checked it. See [`padded_crc32`].
+    pub crc32: u32,
+}
+
+impl UploadReport {
+    /// Returns the average number of bytes transferred per second.
+    pub fn bytes_per_second(&self) -> f64 {
+        throughput(self.bytes, self.elapsed)
+    }
+}
+
+/// The CRC-32 the hub checks uploads with. See [`SpikeConnection::set_crc_algorithm`].
+pub const DEFAULT_CRC_ALGORITHM: &crc::Algorithm<u32> = &crc::CRC_32_ISO_HDLC;
+
+/// Computes the CRC-32 the hub expects for a file, which is padded with zeros to a multiple of 4 bytes.
+pub fn padded_crc32(data: &[u8]) -> u32 {
+    ChunkedCrc::new().update(data)
+}
+
+/// The running CRC-32 of a transfer, which the hub checks after each chunk. See [`SpikeConnection::send_chunks`].
+///
+/// Each chunk is padded with zeros to a multiple of 4 bytes, and the CRC carries on from the CRC of the chunks before it.
+/// As long as every chunk but the last is a multiple of 4 bytes long, the result is the same as [`padded_crc32`] of the whole file.
+#[derive(Copy, Clone)]
+pub struct ChunkedCrc {
+    algorithm: &'static crc::Algorithm<u32>,
+    crc32: Option<u32>,
+}
+
+impl fmt::Debug for ChunkedCrc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChunkedCrc")
+            .field("crc32", &self.crc32)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for ChunkedCrc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChunkedCrc {
+    pub fn new() -> Self {
+        Self::with_algorithm(DEFAULT_CRC_ALGORITHM)
+    }
+
+    /// Like [`ChunkedCrc::new`], but with a different 32 bit CRC algorithm than the hub uses. See [`SpikeConnection::set_crc_algorithm`].
+    pub fn with_algorithm(algorithm: &'static crc::Algorithm<u32>) -> Self {
+        Self {
+            algorithm,
+            crc32: None,
+        }
+    }
+
+    /// Adds a chunk, returning the CRC of all of the chunks so far.
+    pub fn update(&mut self, chunk: &[u8]) -> u32 {
+        let crc = crc::Crc::<u32>::new(self.algorithm);
+        let mut digest = match self.crc32 {
+            // Undo the final XOR and bit reflections, so the digest carries on from where the last chunk left off
+            Some(crc32) => {
+                let mut state = crc32 ^ self.algorithm.xorout;
+                if self.algorithm.refin != self.algorithm.refout {
+                    state = state.reverse_bits();
                 }
-                TX_UUID => {
-                    tx = Some(i);
+                if self.algorithm.refin {
+                    state = state.reverse_bits();
                 }
-                _ => {}
+                crc.digest_with_initial(state)
             }
+            None => crc.digest(),
+        };
+
+        digest.update(chunk);
+        for _ in 0..((4 - (chunk.len() % 4)) % 4) {
+            digest.update(&[0]);
         }
+        *self.crc32.insert(digest.finalize())
+    }
 
-        let tx = tx.ok_or(Error::BadDevice)?;
-        let rx = rx.ok_or(Error::BadDevice)?;
+    /// Returns the CRC of all of the chunks so far, which is the CRC of no data if there weren't any.
+    pub fn value(&self) -> u32 {
+        self.crc32
+            .unwrap_or_else(|| crc::Crc::<u32>::new(self.algorithm).checksum(&[]))
+    }
+}
 
-        connection.subscribe(&tx).await?;
+fn throughput(bytes: usize, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() {
+        0.0
+    } else {
+        bytes as f64 / elapsed.as_secs_f64()
+    }
+}
 
-        let info_request_packet = Self::encode_message(RxMessage::InfoRequest.serialize());
-        connection
-            .write(&rx, &info_request_packet, WriteType::WithoutResponse)
-            .await?;
+/// A message recorded by the exchange log. See [`SpikeConnection::enable_exchange_log`].
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum LoggedMessage {
+    /// A message sent to the hub, in its [`Debug`] representation.
+    Sent(String),
+    /// A message received from the hub.
+    Received(TxMessage),
+}
 
-        let mut notifications = connection.notifications().await?;
-        let response = Self::decode_message(notifications.next().await.unwrap().value);
-        let packet = if let TxMessage::InfoResponse(r) = TxMessage::deserialize(response)? {
-            r
-        } else {
-            Err(Error::WrongMessage)?
-        };
+fn upload_failed(slot: u8, name: &str, error: Error) -> Error {
+    Error::UploadFailed {
+        slot,
+        name: name.to_string(),
+        error: Box::new(error),
+    }
+}
 
-        let rpc_version = (packet.rpc_major, packet.rpc_minor, packet.rpc_build);
-        let firmware_version = (
-            packet.firmware_major,
-            packet.firmware_minor,
-            packet.firmware_build,
-        );
+/// Converts bare `\n` line endings to the `\r\n` the hub expects, leaving existing `\r\n`s alone.
+fn to_crlf(code: &str) -> String {
+    let mut converted = String::with_capacity(code.len());
+    let mut last = None;
+    for c in code.chars() {
+        if c == '\n' && last != Some('\r') {
+            converted.push('\r');
+        }
+        converted.push(c);
+        last = Some(c);
+    }
+    converted
+}
 
-        if packet.product_group_device_type != 0 {
-            return Err(Error::BadDevice);
+/// Which way raw bytes were going, from the hub's perspective like [`RxMessage`] and [`TxMessage`]. See [`SpikeConnection::set_trace`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// Bytes sent by the hub
+    Tx,
+    /// Bytes sent to the hub
+    Rx,
+}
+
+type TraceFn = Box<dyn Fn(Direction, &[u8]) + Send>;
+
+/// Calls the function set by [`SpikeConnection::set_trace`], if there is one.
+fn trace(trace: &std::sync::Mutex<Option<TraceFn>>, direction: Direction, bytes: &[u8]) {
+    let trace = trace.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(trace) = trace.as_ref() {
+        trace(direction, bytes);
+    }
+}
+
+/// The result of [`SpikeConnection::receive_message_or_progress`].
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum MessageOrProgress {
+    /// A message was received.
+    Message(TxMessage),
+    /// No message was received, but the hub sent a notification, so it's still alive.
+    Progress,
+}
+
+/// A console or program flow notification. See [`SpikeConnection::program_event_stream`].
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[non_exhaustive]
+pub enum ProgramEvent {
+    Console(ConsoleNotification),
+    ProgramFlow(ProgramFlowNotification),
+}
+
+/// Anything the hub sends. See [`SpikeConnection::events`].
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[non_exhaustive]
+pub enum SpikeEvent {
+    Response(TxMessage),
+    Console(ConsoleNotification),
+    ProgramFlow(ProgramFlowNotification),
+    Device(DeviceNotification),
+}
+
+/// Whether the hub is connected. See [`SpikeConnection::connection_state`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ConnectionState {
+    Connected,
+    Disconnected,
+}
+
+/// Events about the state of the hub. See [`SpikeConnection::lifecycle_event`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum LifecycleEvent {
+    /// The hub's battery percentage dropped to or below one of the thresholds set with [`SpikeConnection::set_battery_thresholds`].
+    BatteryThresholdCrossed(u8),
+}
+
+#[derive(Default)]
+struct BatteryThresholds {
+    /// Each threshold, along with whether the battery was last above it. This is None until the first battery reading.
+    thresholds: Vec<(u8, Option<bool>)>,
+}
+
+impl BatteryThresholds {
+    /// Creates thresholds that haven't seen a battery reading yet, sorted highest first.
+    fn new(thresholds: &[u8]) -> Self {
+        let mut thresholds = thresholds.to_vec();
+        thresholds.sort_unstable_by(|a, b| b.cmp(a));
+        thresholds.dedup();
+        Self {
+            thresholds: thresholds.into_iter().map(|t| (t, None)).collect(),
         }
+    }
 
-        let (msg_tx, msg_rx) = mpsc::channel(4);
-        let (console_tx, console_rx) = mpsc::channel(4);
-        let (program_flow_tx, program_flow_rx) = mpsc::channel(4);
-        let device_notification = Arc::new(Mutex::new(None));
+    /// Updates the thresholds with a new battery reading, returning the thresholds that were crossed, highest first.
+    fn update(&mut self, battery: u8) -> Vec<u8> {
+        let mut crossed = Vec::new();
+        for (threshold, above) in &mut self.thresholds {
+            match above {
+                None => *above = Some(battery > *threshold),
+                Some(true) if battery <= *threshold => {
+                    *above = Some(false);
+                    crossed.push(*threshold);
+                }
+                Some(false) if battery >= threshold.saturating_add(BATTERY_HYSTERESIS) => {
+                    *above = Some(true);
+                }
+                _ => {}
+            }
+        }
+        crossed
+    }
+}
 
-        let handle = tokio::spawn(filter_thread(
-            msg_tx,
-            device_notification.clone(),
-            notifications,
-            console_tx,
-            program_flow_tx,
+struct ExchangeLog {
+    capacity: usize,
+    messages: VecDeque<LoggedMessage>,
+}
+
+impl ExchangeLog {
+    fn push(&mut self, message: LoggedMessage) {
+        if self.messages.len() == self.capacity {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message);
+    }
+}
+
+impl Drop for SpikeConnection {
+    fn drop(&mut self) {
+        self.msg_handle.abort();
+        // Disconnecting has to be awaited, so it can only be done if there's a runtime to do it on
+        let mut transport = std::mem::replace(&mut self.transport, Box::new(Closed));
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                let _ = transport.disconnect().await;
+            });
+        }
+    }
+}
+
+impl std::fmt::Debug for SpikeConnection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SpikeConnection")
+            .field("info", &self.info)
+            .finish()
+    }
+}
+
+impl SpikeConnection {
+    pub(crate) async fn new(
+        connection: Peripheral,
+        device_types: &[DeviceType],
+        config: SpikeConnectionConfig,
+    ) -> Result<Self> {
+        let transport = Box::new(BleTransport::new(connection));
+        Self::connect_transport(transport, device_types, config).await
+    }
+
+    /// Connects to a SPIKE Prime over a custom [`Transport`].
+    pub async fn from_transport(transport: impl Transport + 'static) -> Result<Self> {
+        Self::from_transport_with_config(transport, SpikeConnectionConfig::default()).await
+    }
+
+    /// Like [`SpikeConnection::from_transport`], but with settings other than the defaults.
+    pub async fn from_transport_with_config(
+        transport: impl Transport + 'static,
+        config: SpikeConnectionConfig,
+    ) -> Result<Self> {
+        Self::connect_transport(Box::new(transport), &[DeviceType::SpikePrime], config).await
+    }
+
+    /// Connects to a SPIKE Prime over its USB serial port at `path`, like `/dev/ttyACM0` or `COM3`. Requires the `serial` feature.
+    #[cfg(feature = "serial")]
+    pub async fn from_serial(path: &str) -> Result<Self> {
+        Self::from_transport(transport::SerialTransport::new(path)).await
+    }
+
+    async fn connect_transport(
+        mut transport: Box<dyn Transport>,
+        device_types: &[DeviceType],
+        config: SpikeConnectionConfig,
+    ) -> Result<Self> {
+        let link = Self::establish(transport.as_mut(), device_types).await?;
+
+        let device_notification = Arc::new(Mutex::new(None));
+        let device_notification_tx = Arc::new(Mutex::new(None));
+        let program_event_tx = Arc::new(Mutex::new(None));
+        let battery_thresholds = Arc::new(Mutex::new(BatteryThresholds::default()));
+        let notification_activity = Arc::new(Notify::new());
+        let max_frame_size = Arc::new(AtomicUsize::new(
+            MAX_FRAME_SIZE_FACTOR * link.info.max_msg_size as usize,
         ));
+        let trace = Arc::new(std::sync::Mutex::new(None));
+        let raw_tx = Arc::new(Mutex::new(None));
+        let ignore_unknown = Arc::new(AtomicBool::new(false));
+        let unknown_messages = Arc::new(AtomicUsize::new(0));
+        let dropped_console = Arc::new(AtomicUsize::new(0));
+        let dropped_program_flow = Arc::new(AtomicUsize::new(0));
+        let connected = Arc::new(AtomicBool::new(true));
+
+        let receivers = spawn_filter_thread(
+            link.notifications,
+            FilterShared {
+                device_notification: device_notification.clone(),
+                device_notification_tx: device_notification_tx.clone(),
+                program_event_tx: program_event_tx.clone(),
+                battery_thresholds: battery_thresholds.clone(),
+                notification_activity: notification_activity.clone(),
+                max_frame_size: max_frame_size.clone(),
+                trace: trace.clone(),
+                raw_tx: raw_tx.clone(),
+                ignore_unknown: ignore_unknown.clone(),
+                unknown_messages: unknown_messages.clone(),
+                dropped_console: dropped_console.clone(),
+                dropped_program_flow: dropped_program_flow.clone(),
+                connected: connected.clone(),
+                config,
+            },
+        );
 
         Ok(SpikeConnection {
-            connection,
-            rx,
-            rpc_version,
-            firmware_version,
-            max_packet_size: packet.max_packet_size,
-            max_message_size: packet.max_msg_size,
-            max_chunk_size: packet.max_chunk_size,
-            msg_rx,
-            console_rx,
-            program_flow_rx,
-            _msg_handle: handle,
+            transport,
+            auto_reconnect: false,
+            reliable_writes: false,
+            chunk_ack_timeout: CHUNK_ACK_TIMEOUT,
+            crc_algorithm: DEFAULT_CRC_ALGORITHM,
+            info: link.info,
+            msg_rx: receivers.msg_rx,
+            console_rx: receivers.console_rx,
+            program_flow_rx: receivers.program_flow_rx,
+            program_event_tx,
+            error_rx: receivers.error_rx,
+            lifecycle_rx: receivers.lifecycle_rx,
+            battery_thresholds,
+            notification_activity,
+            uploaded_crcs: std::sync::Mutex::new(HashMap::new()),
+            exchange_log: Mutex::new(None),
+            throughput_callback: None,
+            msg_handle: receivers.handle,
+            relinked: std::sync::Mutex::new(None),
             device_notification,
+            device_notification_interval: None,
+            device_notification_tx,
+            max_frame_size,
+            trace,
+            raw_tx,
+            raw_rx: None,
+            ignore_unknown,
+            unknown_messages,
+            dropped_console,
+            dropped_program_flow,
+            connected,
+            config,
+            last_started: None,
+            pending: VecDeque::new(),
         })
     }
 
+    /// Connects to the hub, subscribes to its notifications, and asks it for its [`InfoResponse`].
+    /// Returns [`Error::BadDevice`] if the hub isn't one of `device_types`.
+    async fn establish(transport: &mut dyn Transport, device_types: &[DeviceType]) -> Result<Link> {
+        let mut notifications = transport.connect().await?;
+
+        let info_request_packet = cobs::encode(&RxMessage::InfoRequest.serialize());
+        transport
+            .write(&info_request_packet, WriteType::WithoutResponse)
+            .await?;
+
+        // Over some transports the response can be split up, so read until the end of its frame
+        let mut frame = Vec::new();
+        let end = loop {
+            if let Some(end) = frame.iter().position(|b| *b == cobs::DELIMITER) {
+                break end;
+            }
+            frame.extend(notifications.next().await.ok_or(Error::Disconnected)?);
+        };
+        let response = cobs::decode(&frame[..=end])?;
+        // Anything after the response was sent along with it, and is received like the rest
+        let rest = frame.split_off(end + 1);
+        let notifications = if rest.is_empty() {
+            notifications
+        } else {
+            futures::stream::once(async { rest })
+                .chain(notifications)
+                .boxed()
+        };
+        let info = match TxMessage::deserialize(response)? {
+            TxMessage::InfoResponse(r) => r,
+            msg => Err(Error::WrongMessage {
+                expected: InfoResponse::NAME,
+                got: msg.name(),
+            })?,
+        };
+
+        if !device_types.contains(&info.product_group_device_type) {
+            return Err(Error::BadDevice);
+        }
+
+        Ok(Link {
+            info,
+            notifications,
+        })
+    }
+
+    /// Reconnects to the hub after the connection was dropped, like when it went out of range, without having to scan for it again.
+    ///
+    /// Messages and notifications that hadn't been received yet are lost, but settings like the battery thresholds are kept, and device notifications are enabled again if they were before.
+    pub async fn reconnect(&mut self) -> Result<()> {
+        let link = Self::establish(
+            self.transport.as_mut(),
+            &[self.info.product_group_device_type],
+        )
+        .await?;
+
+        self.msg_handle.abort();
+        self.connected.store(true, Ordering::Relaxed);
+        let receivers = spawn_filter_thread(link.notifications, self.filter_shared());
+
+        self.info = link.info;
+        self.replace_receivers(receivers);
+        *self
+            .relinked
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+        self.pending.clear();
+        // The hub could have been rebooted, or had programs uploaded by something else
+        self.uploaded_crcs().clear();
+
+        if let Some(interval) = self.device_notification_interval {
+            self.enable_device_notifications_with_interval(interval)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Returns the state shared with [`filter_thread`], for starting it again.
+    fn filter_shared(&self) -> FilterShared {
+        FilterShared {
+            device_notification: self.device_notification.clone(),
+            device_notification_tx: self.device_notification_tx.clone(),
+            program_event_tx: self.program_event_tx.clone(),
+            battery_thresholds: self.battery_thresholds.clone(),
+            notification_activity: self.notification_activity.clone(),
+            max_frame_size: self.max_frame_size.clone(),
+            trace: self.trace.clone(),
+            raw_tx: self.raw_tx.clone(),
+            ignore_unknown: self.ignore_unknown.clone(),
+            unknown_messages: self.unknown_messages.clone(),
+            dropped_console: self.dropped_console.clone(),
+            dropped_program_flow: self.dropped_program_flow.clone(),
+            connected: self.connected.clone(),
+            config: self.config,
+        }
+    }
+
+    /// Switches to the channels of a newly started [`filter_thread`].
+    fn replace_receivers(&mut self, receivers: FilterReceivers) {
+        self.msg_handle.abort();
+        self.msg_rx = receivers.msg_rx;
+        self.console_rx = receivers.console_rx;
+        self.program_flow_rx = receivers.program_flow_rx;
+        self.error_rx = receivers.error_rx;
+        self.lifecycle_rx = receivers.lifecycle_rx;
+        self.msg_handle = receivers.handle;
+        self.raw_rx = None;
+    }
+
+    /// Switches to the channels of the [`filter_thread`] started by relinking, if it was relinked since this was last called.
+    fn take_relinked(&mut self) {
+        let relinked = self
+            .relinked
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(receivers) = relinked {
+            self.replace_receivers(receivers);
+        }
+    }
+
+    /// Disconnects from the hub, stopping the task that receives its messages.
+    /// Dropping the connection does this too, but in the background, so errors can't be seen. It also only happens if the connection is dropped inside a tokio runtime.
+    pub async fn disconnect(mut self) -> Result<()> {
+        self.close().await
+    }
+
+    /// Does the work of [`SpikeConnection::disconnect`], leaving a connection that can't be used anymore behind.
+    pub(crate) async fn close(&mut self) -> Result<()> {
+        self.msg_handle.abort();
+        let mut transport = std::mem::replace(&mut self.transport, Box::new(Closed));
+        transport.disconnect().await
+    }
+
+    /// Returns the Bluetooth id of the hub, which identifies it without having to ask it for anything. See [`SpikePrime::id`](crate::SpikePrime::id).
+    /// Returns None if the hub isn't connected over Bluetooth.
+    pub fn peripheral_id(&self) -> Option<PeripheralId> {
+        self.transport.peripheral_id()
+    }
+
+    /// Returns whether the hub is still connected, without sending it anything. This is handy for a GUI to check every frame.
+    ///
+    /// The connection finds out the hub disconnected when it stops sending notifications or a write fails, so this can stay true for a moment after the hub goes out of range.
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// Like [`SpikeConnection::is_connected`], but returns a [`ConnectionState`].
+    pub fn connection_state(&self) -> ConnectionState {
+        if self.is_connected() {
+            ConnectionState::Connected
+        } else {
+            ConnectionState::Disconnected
+        }
+    }
+
+    /// Returns the [`InfoResponse`] the hub sent when it was connected to.
+    pub fn info(&self) -> &InfoResponse {
+        &self.info
+    }
+
     /// Returns RPC Version as (major, minor, build)
     pub fn rpc_version(&self) -> (u8, u8, u16) {
-        self.rpc_version
+        (
+            self.info.rpc_major,
+            self.info.rpc_minor,
+            self.info.rpc_build,
+        )
     }
 
     /// Returns Firmware Version as (major, minor, build)
     pub fn firmware_version(&self) -> (u8, u8, u16) {
-        self.firmware_version
+        (
+            self.info.firmware_major,
+            self.info.firmware_minor,
+            self.info.firmware_build,
+        )
     }
 
     pub fn max_packet_size(&self) -> u16 {
-        self.max_packet_size
+        self.info.max_packet_size
     }
 
     pub fn max_message_size(&self) -> u16 {
-        self.max_message_size
+        self.info.max_msg_size
     }
 
     pub fn max_chunk_size(&self) -> u16 {
-        self.max_chunk_size
+        self.info.max_chunk_size
     }
 
     /// Returns the last device notification sent to the computer. [`SpikeConnection::enable_device_notifications`] must have been called for this to return Some.
     /// Returns None if no device notification has been sent, or if device notifications are disabled.
+    ///
+    /// Only the latest notification is kept, so most of them are missed unless this is called very often. To receive every notification, see [`SpikeConnection::device_notification_stream`].
     pub async fn device_notification(&self) -> Option<DeviceNotification> {
         self.device_notification.lock().await.clone()
     }
@@ -166,349 +722,1469 @@ impl SpikeConnection {
         self.device_notification.try_lock().ok()?.clone()
     }
 
+    /// Returns a receiver that gets every [`DeviceNotification`] the hub sends, which is useful for logging sensor data.
+    /// Up to `capacity` notifications are buffered. If the receiver falls behind, new notifications are dropped until it catches up, so responses from the hub aren't held up.
+    ///
+    /// [`SpikeConnection::device_notification`] keeps working while this is in use. Calling this again replaces the previous receiver.
+    pub async fn device_notification_stream(
+        &mut self,
+        capacity: usize,
+    ) -> Receiver<DeviceNotification> {
+        let (tx, rx) = mpsc::channel(capacity.max(1));
+        *self.device_notification_tx.lock().await = Some(tx);
+        rx
+    }
+
     /// Returns and consumes the last [`ConsoleNotification`] sent. If all ConsoleNotifications have been consumed, this function will wait until another is availible.
     pub async fn console_notification(&mut self) -> ConsoleNotification {
+        self.take_relinked();
         self.console_rx.recv().await.expect("BUG")
     }
 
     /// A non-async version of [`SpikeConnection::console_notification`]. Will return None if no [`ConsoleNotification`]s are availible.
     pub fn try_console_notification(&mut self) -> Option<ConsoleNotification> {
+        self.take_relinked();
         self.console_rx.try_recv().ok()
     }
 
+    /// Returns a [`Stream`] of [`ConsoleNotification`]s, which is handy for showing console output live.
+    /// This reads from the same place as [`SpikeConnection::console_notification`], so each notification is only returned by one of them.
+    pub fn console_stream(&mut self) -> impl Stream<Item = ConsoleNotification> + '_ {
+        self.take_relinked();
+        futures::stream::poll_fn(|cx| self.console_rx.poll_recv(cx))
+    }
+
+    /// Returns a [`Stream`] of everything the hub sends, so an app can handle it all in one place instead of waiting on each kind separately.
+    /// The stream ends after returning [`Error::Disconnected`].
+    ///
+    /// This reads from the same places as [`SpikeConnection::receive_message`], [`SpikeConnection::console_notification`], and [`SpikeConnection::program_flow_notification`], so each message is only returned by one of them.
+    /// Device notifications are sent to the stream like [`SpikeConnection::device_notification_stream`], replacing its receiver, so they're dropped if the stream isn't polled often enough.
+    pub fn events(&mut self) -> impl Stream<Item = Result<SpikeEvent>> + '_ {
+        self.take_relinked();
+        let (tx, mut device_rx) = mpsc::channel(EVENT_CAPACITY);
+        let device_notification_tx = self.device_notification_tx.clone();
+        let subscribe = futures::stream::once(async move {
+            *device_notification_tx.lock().await = Some(tx);
+        })
+        .filter_map(async |()| None);
+
+        let mut disconnected = false;
+        let events = futures::stream::poll_fn(move |cx| {
+            if disconnected {
+                return Poll::Ready(None);
+            }
+            if let Some(msg) = self.pending.pop_front() {
+                return Poll::Ready(Some(Ok(SpikeEvent::Response(msg))));
+            }
+            if let Poll::Ready(msg) = self.msg_rx.poll_recv(cx) {
+                let msg = msg.unwrap_or(Err(Error::Disconnected));
+                match &msg {
+                    Ok(msg) => self.log_received(msg),
+                    Err(Error::Disconnected) => disconnected = true,
+                    Err(_) => {}
+                }
+                return Poll::Ready(Some(msg.map(SpikeEvent::Response)));
+            }
+            if let Poll::Ready(Some(notification)) = self.console_rx.poll_recv(cx) {
+                return Poll::Ready(Some(Ok(SpikeEvent::Console(notification))));
+            }
+            if let Poll::Ready(Some(notification)) = self.program_flow_rx.poll_recv(cx) {
+                return Poll::Ready(Some(Ok(SpikeEvent::ProgramFlow(notification))));
+            }
+            if let Poll::Ready(Some(notification)) = device_rx.poll_recv(cx) {
+                return Poll::Ready(Some(Ok(SpikeEvent::Device(notification))));
+            }
+            Poll::Pending
+        });
+        subscribe.chain(events)
+    }
+
     /// Returns and consumes the last [`ProgramFlowNotification`] sent. If all ProgramFlowNotifications have been consumed, this function will wait until another is availible.
     pub async fn program_flow_notification(&mut self) -> ProgramFlowNotification {
+        self.take_relinked();
         self.program_flow_rx.recv().await.expect("BUG")
     }
 
     /// A non-async version of [`SpikeConnection::program_flow_notification`]. Will return None if no [`ProgramFlowNotification`]s are availible.
     pub fn try_program_flow_notification(&mut self) -> Option<ProgramFlowNotification> {
+        self.take_relinked();
         self.program_flow_rx.try_recv().ok()
     }
 
+    /// Waits for the next program to stop, like when it finishes or is stopped with the hub's buttons. Start notifications are skipped.
+    ///
+    /// The hub doesn't say which slot a [`ProgramFlowNotification`] is for, but only one program can run at a time, so this is the one that was started last.
+    /// Returns [`Error::Disconnected`] if the hub disconnects first.
+    /// While [`SpikeConnection::program_event_stream`]'s receiver is alive, this won't receive anything.
+    pub async fn await_next_program_stop(&mut self) -> Result<()> {
+        self.take_relinked();
+        loop {
+            let notification = self
+                .program_flow_rx
+                .recv()
+                .await
+                .ok_or(Error::Disconnected)?;
+            if notification.program_action == ProgramAction::Stop {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Returns a receiver for console and program flow notifications, in the order the hub sent them. This is useful for telling whether a program printed something before or after it stopped.
+    ///
+    /// Once this is called, console and program flow notifications are only sent to the returned receiver, so [`SpikeConnection::console_notification`] and [`SpikeConnection::program_flow_notification`] won't receive them until it's dropped.
+    /// Their order is only kept within the receiver, not across those functions. Calling this again replaces the previous receiver.
+    ///
+    /// The receiver buffers as many events as [`SpikeConnectionConfig::console_capacity`]. Once it's full, new events are dropped and counted, see [`SpikeConnection::dropped_console_count`].
+    pub async fn program_event_stream(&mut self) -> Receiver<ProgramEvent> {
+        let (tx, rx) = mpsc::channel(self.config.console_capacity.max(1));
+        *self.program_event_tx.lock().await = Some(tx);
+        rx
+    }
+
+    /// Returns and consumes the oldest error produced while decoding a message from the hub. If no errors are availible, this function will wait until one is.
+    ///
+    /// Messages that fail to decode are reported here instead of through [`SpikeConnection::receive_message`], so a corrupt frame can't be mistaken for the response to a request.
+    /// Only the last few errors are kept; if they aren't consumed, newer errors are dropped.
+    pub async fn decode_error(&mut self) -> Error {
+        self.take_relinked();
+        self.error_rx.recv().await.expect("BUG")
+    }
+
+    /// A non-async version of [`SpikeConnection::decode_error`]. Will return None if no errors are availible.
+    pub fn try_decode_error(&mut self) -> Option<Error> {
+        self.take_relinked();
+        self.error_rx.try_recv().ok()
+    }
+
+    /// Sets whether messages of types the crate doesn't know about are dropped, instead of being reported as [`Error::UnknownMessage`] by [`SpikeConnection::decode_error`].
+    /// This is useful with newer firmware that sends messages this crate doesn't support yet. They're reported by default.
+    ///
+    /// Either way, they're counted by [`SpikeConnection::unknown_message_count`], and never returned by [`SpikeConnection::receive_message`].
+    /// Unknown devices in a [`DeviceNotification`] aren't affected by this, since the rest of the notification is still useful. They show up as [`DeviceMessage::Unknown`].
+    pub fn set_ignore_unknown(&mut self, ignore: bool) {
+        self.ignore_unknown.store(ignore, Ordering::Relaxed);
+    }
+
+    /// Returns how many messages of types the crate doesn't know about have been received. Unknown devices in a [`DeviceNotification`] aren't counted.
+    pub fn unknown_message_count(&self) -> usize {
+        self.unknown_messages.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many console notifications were dropped because they weren't read fast enough, and their buffer was full. See [`SpikeConnectionConfig::console_capacity`].
+    pub fn dropped_console_count(&self) -> usize {
+        self.dropped_console.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many program flow notifications were dropped because they weren't read fast enough, and their buffer was full. See [`SpikeConnectionConfig::program_flow_capacity`].
+    pub fn dropped_program_flow_count(&self) -> usize {
+        self.dropped_program_flow.load(Ordering::Relaxed)
+    }
+
+    /// Returns and consumes the oldest [`LifecycleEvent`]. If all events have been consumed, this function will wait until another is availible.
+    /// Only the last few events are kept; if they aren't consumed, newer events are dropped.
+    pub async fn lifecycle_event(&mut self) -> LifecycleEvent {
+        self.take_relinked();
+        self.lifecycle_rx.recv().await.expect("BUG")
+    }
+
+    /// A non-async version of [`SpikeConnection::lifecycle_event`]. Will return None if no [`LifecycleEvent`]s are availible.
+    pub fn try_lifecycle_event(&mut self) -> Option<LifecycleEvent> {
+        self.take_relinked();
+        self.lifecycle_rx.try_recv().ok()
+    }
+
+    /// Sets the battery percentages that send a [`LifecycleEvent::BatteryThresholdCrossed`] when the battery drops to or below them, replacing any previous thresholds.
+    /// Device notifications must be enabled for the battery to be monitored, see [`SpikeConnection::enable_device_notifications`].
+    ///
+    /// The first battery reading after this is called doesn't send any events, even if the battery is already below a threshold. After a threshold is crossed, the battery has to rise a couple percent above it before it can be crossed again, so a reading jittering around a threshold doesn't send lots of events.
+    pub async fn set_battery_thresholds(&self, thresholds: &[u8]) {
+        *self.battery_thresholds.lock().await = BatteryThresholds::new(thresholds);
+    }
+
     /// Sends a message to the SPIKE Prime.
     pub async fn send_message<'a, R: Into<RxMessage<'a>>>(&self, message: R) -> Result<()> {
         let into = message.into();
+        Self::check_chunk_size(&into, self.info.max_chunk_size.into())?;
         #[cfg(feature = "debug_logging")]
         println!("Sending message: {into:?}");
+        if let Some(log) = self.exchange_log.lock().await.as_mut() {
+            log.push(LoggedMessage::Sent(format!("{into:?}")));
+        }
 
-        let message = into.serialize();
-        if message.len() > self.max_message_size as usize {
-            return Err(Error::OversizedMessage);
+        // Chunks are acknowledged by the hub anyway, so they don't need to be written reliably
+        let write_type =
+            if self.reliable_writes && !matches!(into, RxMessage::TransferChunkRequest(_)) {
+                WriteType::WithResponse
+            } else {
+                WriteType::WithoutResponse
+            };
+        self.send_frame(into.serialize(), write_type).await
+    }
+
+    /// Sends a message that isn't an [`RxMessage`], like one the crate doesn't know about yet. `bytes` starts with the message's ID, and is framed like any other message.
+    /// This is meant for experimenting with the protocol, so prefer [`SpikeConnection::send_message`] when possible.
+    pub async fn send_raw(&mut self, bytes: &[u8]) -> Result<()> {
+        #[cfg(feature = "debug_logging")]
+        println!("Sending raw message: {bytes:02x?}");
+        if let Some(log) = self.exchange_log.lock().await.as_mut() {
+            log.push(LoggedMessage::Sent(format!("{bytes:02x?}")));
         }
-        let bytes = Self::encode_message(message);
-        for i in bytes.chunks(self.max_packet_size.into()) {
-            self.write_bytes(i).await?;
+
+        let write_type = if self.reliable_writes {
+            WriteType::WithResponse
+        } else {
+            WriteType::WithoutResponse
+        };
+        self.send_frame(bytes.to_vec(), write_type).await
+    }
+
+    /// Receives the next message from the hub without deserializing it, so messages the crate doesn't know about can be seen. The message starts with its ID.
+    ///
+    /// Messages are only kept for this from the first time it's called, and they're still received as usual too. If they aren't read, only the first 16 are kept.
+    /// Returns [`Error::Disconnected`] once the hub disconnects.
+    pub async fn receive_raw(&mut self) -> Result<Vec<u8>> {
+        self.take_relinked();
+        if self.raw_rx.is_none() {
+            let (tx, rx) = mpsc::channel(RAW_FRAME_CAPACITY);
+            *self.raw_tx.lock().await = Some(tx);
+            self.raw_rx = Some(rx);
         }
+        let raw_rx = self.raw_rx.as_mut().expect("BUG");
+        raw_rx.recv().await.ok_or(Error::Disconnected)
+    }
 
+    /// Frames a serialized message and writes it in packets the hub can receive.
+    async fn send_frame(&self, message: Vec<u8>, write_type: WriteType) -> Result<()> {
+        let bytes = Self::encode_checked(message, self.info.max_msg_size.into())?;
+        for i in bytes.chunks(self.info.max_packet_size.into()) {
+            self.write_bytes(i, write_type).await?;
+        }
         Ok(())
     }
 
-    pub async fn get_hub_name(&mut self) -> Result<String> {
-        self.send_message(RxMessage::GetHubNameRequest).await?;
-        if let TxMessage::GetHubNameResponse(r) = self.receive_message().await? {
-            Ok(r.name)
-        } else {
-            Err(Error::WrongMessage)
+    /// Sends a message to the hub, and waits for the response of type `R` to it, like [`GetHubNameResponse`].
+    /// Any other messages received in the meantime are set aside, and can still be received with [`SpikeConnection::receive_message`].
+    /// Returns [`Error::Timeout`] if the response isn't received within 5 seconds, or [`Error::WrongMessage`] if too many other messages are received first.
+    pub async fn request<'a, R: ResponseMessage>(
+        &mut self,
+        message: impl Into<RxMessage<'a>>,
+    ) -> Result<R> {
+        self.send_message(message).await?;
+        tokio::time::timeout(RESPONSE_TIMEOUT, self.receive_response())
+            .await
+            .map_err(|_| Error::Timeout)?
+    }
+
+    /// Receives messages until one of type `R` is received. Any others are set aside to be returned by [`SpikeConnection::receive_message`] later.
+    /// Gives up with [`Error::WrongMessage`] if too many other messages are received first.
+    async fn receive_response<R: ResponseMessage>(&mut self) -> Result<R> {
+        // The response could have been set aside while waiting for a different one
+        let mut response = None;
+        for msg in std::mem::take(&mut self.pending) {
+            match response {
+                None => match R::from_message(msg) {
+                    Ok(r) => response = Some(r),
+                    Err(msg) => self.pending.push_back(msg),
+                },
+                Some(_) => self.pending.push_back(msg),
+            }
         }
+        if let Some(response) = response {
+            return Ok(response);
+        }
+
+        for _ in 0..MAX_PENDING_MESSAGES {
+            match R::from_message(self.receive_new_message().await?) {
+                Ok(response) => return Ok(response),
+                Err(msg) => {
+                    if self.pending.len() == MAX_PENDING_MESSAGES {
+                        self.pending.pop_front();
+                    }
+                    self.pending.push_back(msg);
+                }
+            }
+        }
+        Err(Error::WrongMessage {
+            expected: R::NAME,
+            got: self.pending.back().map_or("nothing", TxMessage::name),
+        })
+    }
+
+    /// Sends a tunnel message to the hub, and returns the payload of the tunnel message it sends back.
+    /// The protocol has no way to tell which message a reply is for, so this assumes the next tunnel message from the hub is the reply.
+    pub async fn tunnel(&mut self, payload: &[u8]) -> Result<Vec<u8>> {
+        let response: TunnelNotification = self.request(TunnelMessage { payload }).await?;
+        Ok(response.payload)
+    }
+
+    pub async fn get_hub_name(&mut self) -> Result<String> {
+        let response: GetHubNameResponse = self.request(RxMessage::GetHubNameRequest).await?;
+        Ok(response.name)
     }
 
     pub async fn get_hub_uuid(&mut self) -> Result<Uuid> {
-        self.send_message(RxMessage::DeviceUuidRequest).await?;
-        let uuid = if let TxMessage::DeviceUuidResponse(r) = self.receive_message().await? {
-            r.uuid
-        } else {
-            return Err(Error::WrongMessage);
-        };
-        Ok(uuid)
+        let response: DeviceUuidResponse = self.request(RxMessage::DeviceUuidRequest).await?;
+        Ok(response.uuid)
     }
 
+    /// Sets the hub's name. Returns [`Error::NameTooLong`] or [`Error::EmptyName`] without sending anything if the hub can't store the name, see [`validate_hub_name`].
     pub async fn set_hub_name(&mut self, name: &str) -> Result<()> {
-        self.send_message(SetHubNameRequest { name }).await?;
-
-        let status = if let TxMessage::SetHubNameResponse(r) = self.receive_message().await? {
-            r.response_status
-        } else {
-            return Err(Error::WrongMessage);
-        };
-        if status == ResponseStatus::NotAcknowledged {
+        validate_hub_name(name)?;
+        let response: SetHubNameResponse = self.request(SetHubNameRequest { name }).await?;
+        if response.response_status == ResponseStatus::NotAcknowledged {
             return Err(Error::NotAcknowledged("SetHubNameRequest", None));
         }
         Ok(())
     }
 
+    /// Sets the hub's name, then asks the hub for its name to make sure it was stored.
+    /// Returns [`Error::VerificationFailed`] if the hub has a different name.
+    pub async fn set_hub_name_verified(&mut self, name: &str) -> Result<()> {
+        self.set_hub_name(name).await?;
+        let actual = self.get_hub_name().await?;
+        if actual != name {
+            return Err(Error::VerificationFailed {
+                expected: name.to_string(),
+                actual,
+            });
+        }
+        Ok(())
+    }
+
     /// Enables device notifications to be sent to the client. Call [`SpikeConnection::device_notification`] to receive the notification.
-    /// A new notification will be sent every 10 ms.
+    /// A new notification will be sent every 10 ms, see [`SpikeConnection::enable_device_notifications_with_interval`] to change that.
     pub async fn enable_device_notifications(&mut self) -> Result<()> {
-        self.send_message(DeviceNotificationRequest {
-            interval: DEVICE_NOTIFICATION_INTERVAL,
-        })
-        .await?;
-        let status =
-            if let TxMessage::DeviceNotificationResponse(r) = self.receive_message().await? {
-                r.response_status
-            } else {
-                return Err(Error::WrongMessage);
-            };
-        if status == ResponseStatus::NotAcknowledged {
-            return Err(Error::NotAcknowledged("DeviceNotificationRequest", None));
+        self.enable_device_notifications_with_interval(DEVICE_NOTIFICATION_INTERVAL)
+            .await
+    }
+
+    /// Returns the hub's battery percentage.
+    ///
+    /// The protocol has no message for this, so device notifications are enabled until one with the battery is received, then disabled again.
+    /// If they were already enabled, the battery from the last one is returned instead. Returns [`Error::Timeout`] if none is received within 5 seconds.
+    pub async fn battery_level(&mut self) -> Result<u8> {
+        let was_enabled = self.device_notification_interval.is_some();
+        if !was_enabled {
+            self.enable_device_notifications().await?;
+        }
+        let battery = tokio::time::timeout(RESPONSE_TIMEOUT, self.next_battery())
+            .await
+            .map_err(|_| Error::Timeout);
+        if !was_enabled {
+            self.disable_device_notifications().await?;
         }
+        battery
+    }
 
-        Ok(())
+    /// Waits for a device notification with the battery in it, unless the last one already had it.
+    async fn next_battery(&self) -> u8 {
+        loop {
+            // Created first, so a notification received while checking isn't missed
+            let notified = self.notification_activity.notified();
+            let notification = self.device_notification.lock().await;
+            if let Some(battery) = notification.as_ref().and_then(|n| n.battery()) {
+                return battery;
+            }
+            drop(notification);
+            notified.await;
+        }
+    }
+
+    /// Waits until a device is connected to `port`, returning its latest reading. This is handy at the start of a program, since the hub can take a moment to notice devices.
+    ///
+    /// Device notifications are enabled if they weren't already, and left enabled. Returns [`Error::Timeout`] if no device shows up on `port` within `timeout`.
+    pub async fn wait_for_device(
+        &mut self,
+        port: HubPort,
+        timeout: Duration,
+    ) -> Result<DeviceMessage> {
+        if self.device_notification_interval.is_none() {
+            self.enable_device_notifications().await?;
+        }
+        tokio::time::timeout(timeout, self.next_device(port))
+            .await
+            .map_err(|_| Error::Timeout)
+    }
+
+    /// Waits for a device notification with a device on `port`, unless the last one already had one.
+    async fn next_device(&self, port: HubPort) -> DeviceMessage {
+        loop {
+            let notified = self.notification_activity.notified();
+            let notification = self.device_notification.lock().await;
+            if let Some(device) = notification.as_ref().and_then(|n| n.device(port)) {
+                return device.clone();
+            }
+            drop(notification);
+            notified.await;
+        }
     }
 
     /// Disables device notifications.
     pub async fn disable_device_notifications(&mut self) -> Result<()> {
-        self.send_message(DeviceNotificationRequest { interval: 0 })
-            .await?;
-        let status =
-            if let TxMessage::DeviceNotificationResponse(r) = self.receive_message().await? {
-                r.response_status
-            } else {
-                return Err(Error::WrongMessage);
-            };
-        if status == ResponseStatus::NotAcknowledged {
+        self.enable_device_notifications_with_interval(0).await
+    }
+
+    /// Enables device notifications, sending a new one every `interval` milliseconds. Longer intervals can help if the Bluetooth connection is congested.
+    /// An interval of 0 disables them, just like [`SpikeConnection::disable_device_notifications`].
+    pub async fn enable_device_notifications_with_interval(&mut self, interval: u16) -> Result<()> {
+        let response: DeviceNotificationResponse =
+            self.request(DeviceNotificationRequest { interval }).await?;
+        if response.response_status == ResponseStatus::NotAcknowledged {
             return Err(Error::NotAcknowledged("DeviceNotificationRequest", None));
         }
-        *self.device_notification.lock().await = None;
+        if interval == 0 {
+            *self.device_notification.lock().await = None;
+            self.device_notification_interval = None;
+        } else {
+            self.device_notification_interval = Some(interval);
+        }
 
         Ok(())
     }
 
-    async fn write_bytes(&self, bytes: &[u8]) -> Result<()> {
-        self.connection
-            .write(&self.rx, bytes, WriteType::WithoutResponse)
-            .await?;
-        Ok(())
+    /// Returns whether device notifications were enabled over this connection.
+    pub fn device_notifications_enabled(&self) -> bool {
+        self.device_notification_interval.is_some()
+    }
+
+    /// Returns the interval device notifications are sent at in milliseconds, or None if they're disabled.
+    pub fn device_notification_interval(&self) -> Option<u16> {
+        self.device_notification_interval
+    }
+
+    /// Sets how many bytes of a frame from the hub are buffered before giving up on it, which keeps a lost delimiter from making the connection buffer forever.
+    /// Frames that get bigger are reported as [`Error::MalformedFrame`] through [`SpikeConnection::decode_error`]. Defaults to 4 times [`SpikeConnection::max_message_size`].
+    pub fn set_max_frame_size(&self, bytes: usize) {
+        self.max_frame_size.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Sets whether to automatically reconnect to the hub if it disconnects. This is disabled by default.
+    ///
+    /// When enabled, if sending a message fails because the hub disconnected, the connection will try to reconnect once and resend it, only returning [`Error::Disconnected`] if that fails.
+    /// Any messages the hub sent while it was disconnected are lost, along with any that hadn't been received yet, like after [`SpikeConnection::reconnect`].
+    pub fn set_auto_reconnect(&mut self, enabled: bool) {
+        self.auto_reconnect = enabled;
+    }
+
+    /// Sets whether to wait for the Bluetooth adapter to confirm each write, which keeps messages from being dropped on a congested connection, but is slower. This is disabled by default.
+    /// Chunks sent by [`SpikeConnection::send_chunks`] are never written this way, since the hub acknowledges each of them anyway.
+    pub fn set_reliable_writes(&mut self, enabled: bool) {
+        self.reliable_writes = enabled;
+    }
+
+    /// Sets how long [`SpikeConnection::send_chunks`] waits for the hub to acknowledge each chunk before giving up with [`Error::UploadStalled`]. Defaults to 5 seconds.
+    pub fn set_chunk_ack_timeout(&mut self, timeout: Duration) {
+        self.chunk_ack_timeout = timeout;
+    }
+
+    /// Sets the CRC algorithm uploads are checked with, in case a firmware update changes it. Defaults to [`DEFAULT_CRC_ALGORITHM`], which is what the hub uses now.
+    /// The algorithm has to be 32 bits wide.
+    pub fn set_crc_algorithm(&mut self, algorithm: &'static crc::Algorithm<u32>) {
+        self.crc_algorithm = algorithm;
+    }
+
+    /// Computes the CRC the hub expects for a file, like [`padded_crc32`] but with the algorithm set by [`SpikeConnection::set_crc_algorithm`].
+    fn file_crc32(&self, data: &[u8]) -> u32 {
+        ChunkedCrc::with_algorithm(self.crc_algorithm).update(data)
+    }
+
+    /// Sets a function to call with the raw bytes of every frame sent to the hub, and every notification received from it before it's decoded.
+    /// This is handy for debugging the protocol, like when the hub sends a message that can't be decoded.
+    pub fn set_trace(&mut self, trace: impl Fn(Direction, &[u8]) + Send + 'static) {
+        *self.trace.lock().unwrap_or_else(PoisonError::into_inner) = Some(Box::new(trace));
+    }
+
+    /// Removes the function set by [`SpikeConnection::set_trace`].
+    pub fn clear_trace(&mut self) {
+        *self.trace.lock().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+
+    fn uploaded_crcs(&self) -> std::sync::MutexGuard<'_, HashMap<u8, (String, u32)>> {
+        self.uploaded_crcs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    async fn write_bytes(&self, bytes: &[u8], write_type: WriteType) -> Result<()> {
+        trace(&self.trace, Direction::Rx, bytes);
+        let mut result = self.transport.write(bytes, write_type).await;
+        if let Err(Error::Disconnected) = result
+            && self.auto_reconnect
+        {
+            result = match self.transport.relink().await {
+                Ok(notifications) => {
+                    self.uploaded_crcs().clear();
+                    // The old task stops receiving once the hub disconnects, so start another before anything is sent
+                    self.msg_handle.abort();
+                    self.connected.store(true, Ordering::Relaxed);
+                    let receivers = spawn_filter_thread(notifications, self.filter_shared());
+                    *self.relinked.lock().unwrap_or_else(PoisonError::into_inner) = Some(receivers);
+                    self.transport.write(bytes, write_type).await
+                }
+                Err(e) => Err(e),
+            };
+        }
+        // Only relinking sets this back, since a write going through doesn't mean anything is being received
+        if let Err(Error::Disconnected) = result {
+            self.connected.store(false, Ordering::Relaxed);
+        }
+        result
     }
 
     /// Receives a message from the device. This function will never return [`DeviceNotification`], [`ConsoleNotification`], or [`ProgramFlowNotification`]. To receive those, see [`SpikeConnection::device_notification`], [`SpikeConnection::console_notification`], or [`SpikeConnection::program_flow_notification`] respectively.
+    /// Messages that couldn't be decoded are reported through [`SpikeConnection::decode_error`] instead.
+    /// Returns [`Error::Disconnected`] once the hub disconnects, like when it's turned off or goes out of range.
+    ///
+    /// Messages that were skipped while waiting for a response, see [`SpikeConnection::request`], are returned first.
     pub async fn receive_message(&mut self) -> Result<TxMessage> {
-        let msg = self.msg_rx.recv().await.unwrap()?;
+        if let Some(msg) = self.pending.pop_front() {
+            return Ok(msg);
+        }
+        self.receive_new_message().await
+    }
+
+    /// Like [`SpikeConnection::receive_message`], but returns [`Error::Timeout`] if no message is received within `timeout`.
+    pub async fn receive_message_timeout(&mut self, timeout: Duration) -> Result<TxMessage> {
+        tokio::time::timeout(timeout, self.receive_message())
+            .await
+            .map_err(|_| Error::Timeout)?
+    }
+
+    /// Receives a message from the hub, ignoring any pending messages.
+    async fn receive_new_message(&mut self) -> Result<TxMessage> {
+        self.take_relinked();
+        let msg = self
+            .msg_rx
+            .recv()
+            .await
+            .unwrap_or(Err(Error::Disconnected))?;
         #[cfg(feature = "debug_logging")]
         println!("Received message: {msg:?}");
+        self.log_received(&msg);
         Ok(msg)
     }
 
     /// A non-async version of [`SpikeConnection::receive_message`]. Will return None if no messages are availible.
     pub fn try_receive_message(&mut self) -> Option<Result<TxMessage>> {
-        self.msg_rx.try_recv().ok()
+        if let Some(msg) = self.pending.pop_front() {
+            return Some(Ok(msg));
+        }
+        self.take_relinked();
+        let msg = match self.msg_rx.try_recv() {
+            Ok(msg) => msg,
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => Err(Error::Disconnected),
+        };
+        if let Ok(msg) = &msg {
+            self.log_received(msg);
+        }
+        Some(msg)
+    }
+
+    /// Like [`SpikeConnection::receive_message`], but returns [`MessageOrProgress::Progress`] if the hub sends a [`DeviceNotification`], [`ConsoleNotification`], or [`ProgramFlowNotification`] before a message is received.
+    /// This can tell apart a hub that isn't sending anything from one that's busy sending notifications. The notifications aren't consumed, and can still be received as usual.
+    pub async fn receive_message_or_progress(&mut self) -> Result<MessageOrProgress> {
+        let activity = self.notification_activity.clone();
+        let notified = activity.notified();
+        tokio::select! {
+            biased;
+            msg = self.receive_message() => msg.map(MessageOrProgress::Message),
+            _ = notified => Ok(MessageOrProgress::Progress),
+        }
+    }
+
+    fn log_received(&mut self, msg: &TxMessage) {
+        if let Some(log) = self.exchange_log.get_mut().as_mut() {
+            log.push(LoggedMessage::Received(msg.clone()));
+        }
+    }
+
+    /// Starts keeping a log of the last `capacity` messages sent to and received from the hub, which can be read with [`SpikeConnection::last_exchange`].
+    /// This is useful for figuring out what led up to an error, like a message not being acknowledged. The log is disabled by default, since it clones every message.
+    pub async fn enable_exchange_log(&self, capacity: usize) {
+        *self.exchange_log.lock().await = Some(ExchangeLog {
+            capacity: capacity.max(1),
+            messages: VecDeque::new(),
+        });
+    }
+
+    /// Stops logging messages, and clears the log.
+    pub async fn disable_exchange_log(&self) {
+        *self.exchange_log.lock().await = None;
+    }
+
+    /// Returns the logged messages, oldest first. Will return an empty [`Vec`] if the exchange log is disabled. See [`SpikeConnection::enable_exchange_log`].
+    pub async fn last_exchange(&self) -> Vec<LoggedMessage> {
+        match self.exchange_log.lock().await.as_ref() {
+            Some(log) => log.messages.iter().cloned().collect(),
+            None => Vec::new(),
+        }
     }
 
     /// Repeatedly sends a [`TransferChunkRequest`] message in order to transfer data. Some messages are required to follow them with this message, so this function can help with those.
-    pub async fn send_chunks(&mut self, data: Vec<u8>) -> Result<()> {
-        let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
-        let mut digest = crc.digest();
-        for i in (0..data.len()).step_by(self.max_chunk_size as usize) {
-            let slice = &data[i..(i + self.max_chunk_size as usize).min(data.len())];
-            digest.update(slice);
-            for _ in 0..((4 - (slice.len() % 4)) % 4) {
-                digest.update(&[0]);
-            }
+    /// Returns [`Error::UploadStalled`] if the hub doesn't respond to a chunk in time, see [`SpikeConnection::set_chunk_ack_timeout`].
+    pub async fn send_chunks(&mut self, data: Vec<u8>) -> Result<UploadReport> {
+        self.send_chunks_with_progress(data, |_, _| {}).await
+    }
+
+    /// Like [`SpikeConnection::send_chunks`], but calls `progress` with the number of bytes sent so far and the total number of bytes after each chunk is acknowledged.
+    /// This is useful for showing a progress bar.
+    pub async fn send_chunks_with_progress(
+        &mut self,
+        data: Vec<u8>,
+        progress: impl FnMut(usize, usize),
+    ) -> Result<UploadReport> {
+        self.send_chunks_from(data, 0, progress, None).await
+    }
 
-            let crc32 = digest.finalize();
-            digest = crc.digest_with_initial(crc32);
+    /// Like [`SpikeConnection::send_chunks_with_progress`], but skips the first `offset` bytes, which the hub already has.
+    /// The running CRC still covers the skipped bytes, so the hub can check the whole file.
+    /// If `offset` is in the middle of a chunk, only the rest of that chunk is sent, with the CRC of everything up to its end.
+    /// Returns [`Error::Cancelled`] before sending the next chunk once `cancel` is cancelled.
+    async fn send_chunks_from(
+        &mut self,
+        data: Vec<u8>,
+        offset: usize,
+        mut progress: impl FnMut(usize, usize),
+        cancel: Option<&CancellationToken>,
+    ) -> Result<UploadReport> {
+        let start = Instant::now();
+        let mut chunks = 0;
+        let mut crc32 = None;
+        let offset = offset.min(data.len());
+        let chunk_size = self.info.max_chunk_size as usize;
+        for (index, chunk) in self.chunk_iter(&data).enumerate() {
+            let i = index * chunk_size;
+            let end = i + chunk.payload.len();
+            crc32 = Some(chunk.crc32);
+            if end <= offset {
+                continue;
+            }
+            if cancel.is_some_and(CancellationToken::is_cancelled) {
+                return Err(Error::Cancelled);
+            }
 
+            // The hub already has the start of a chunk that `offset` is in the middle of
+            let i = i.max(offset);
             self.send_message(TransferChunkRequest {
-                crc32,
-                payload: slice,
+                crc32: chunk.crc32,
+                payload: &data[i..end],
             })
             .await?;
-            let status = if let TxMessage::TransferChunkResponse(r) = self.receive_message().await?
-            {
-                r.response_status
-            } else {
-                return Err(Error::WrongMessage);
-            };
-            if status == ResponseStatus::NotAcknowledged {
+            let response: TransferChunkResponse =
+                tokio::time::timeout(self.chunk_ack_timeout, self.receive_response())
+                    .await
+                    .map_err(|_| Error::UploadStalled { chunk_index: index })??;
+            if response.response_status == ResponseStatus::NotAcknowledged {
                 return Err(Error::NotAcknowledged("TransferChunkRequest", Some(i)));
             }
+
+            chunks += 1;
+            progress(end, data.len());
+            if let Some(callback) = &mut self.throughput_callback {
+                callback(throughput(end - offset, start.elapsed()));
+            }
         }
 
-        Ok(())
+        Ok(UploadReport {
+            crc32: crc32.unwrap_or_else(|| ChunkedCrc::with_algorithm(self.crc_algorithm).value()),
+            bytes: data.len() - offset,
+            chunks,
+            elapsed: start.elapsed(),
+        })
+    }
+
+    /// Splits `data` into the [`TransferChunkRequest`]s [`SpikeConnection::send_chunks`] sends, each as big as the hub allows, with the running CRC of the chunks so far.
+    /// Useful for counting the chunks of an upload beforehand, or sending them some other way.
+    pub fn chunk_iter<'a>(
+        &self,
+        data: &'a [u8],
+    ) -> impl Iterator<Item = TransferChunkRequest<'a>> + use<'a> {
+        let mut crc32 = ChunkedCrc::with_algorithm(self.crc_algorithm);
+        data.chunks(self.info.max_chunk_size as usize)
+            .map(move |payload| TransferChunkRequest {
+                crc32: crc32.update(payload),
+                payload,
+            })
+    }
+
+    /// Sets a function to call with the average number of bytes transferred per second so far, each time a chunk is acknowledged by [`SpikeConnection::send_chunks`].
+    pub fn set_throughput_callback(&mut self, callback: impl FnMut(f64) + Send + Sync + 'static) {
+        self.throughput_callback = Some(Box::new(callback));
+    }
+
+    /// Removes the function set by [`SpikeConnection::set_throughput_callback`].
+    pub fn clear_throughput_callback(&mut self) {
+        self.throughput_callback = None;
     }
 
     /// Starts a program on the hub by sending a [`ProgramFlowRequest`] with [`ProgramAction::Start`].
-    pub async fn start_program(&mut self, slot: u8) -> Result<()> {
-        self.send_message(ProgramFlowRequest {
-            program_action: ProgramAction::Start,
-            program_slot: slot,
-        })
-        .await?;
+    pub async fn start_program<S>(&mut self, slot: S) -> Result<()>
+    where
+        S: TryInto<ProgramSlot>,
+        Error: From<S::Error>,
+    {
+        self.program_flow(ProgramAction::Start, slot.try_into()?)
+            .await
+    }
 
-        let status = if let TxMessage::ProgramFlowResponse(r) = self.receive_message().await? {
-            r.response_status
-        } else {
-            return Err(Error::WrongMessage);
-        };
-        if status == ResponseStatus::NotAcknowledged {
+    /// Stops a program on the hub by sending a [`ProgramFlowRequest`] with [`ProgramAction::Stop`].
+    pub async fn stop_program<S>(&mut self, slot: S) -> Result<()>
+    where
+        S: TryInto<ProgramSlot>,
+        Error: From<S::Error>,
+    {
+        self.program_flow(ProgramAction::Stop, slot.try_into()?)
+            .await
+    }
+
+    /// Makes sure no program is running on the hub, like before uploading a new one, so a leftover program can't keep driving motors.
+    ///
+    /// Only one program can run at a time, so this sends a single stop, for the slot last started over this connection, or slot 0 if none was.
+    /// If the hub doesn't acknowledge it or doesn't respond, nothing was running, so that isn't an error.
+    pub async fn stop_all_programs(&mut self) -> Result<()> {
+        let slot = self.last_started.unwrap_or_default();
+        match self.program_flow(ProgramAction::Stop, slot).await {
+            Ok(()) | Err(Error::NotAcknowledged(..) | Error::Timeout) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn program_flow(
+        &mut self,
+        program_action: ProgramAction,
+        slot: ProgramSlot,
+    ) -> Result<()> {
+        let response: ProgramFlowResponse = self
+            .request(ProgramFlowRequest {
+                program_action,
+                program_slot: slot.get(),
+            })
+            .await?;
+        if response.response_status == ResponseStatus::NotAcknowledged {
             return Err(Error::NotAcknowledged("ProgramFlowRequest", None));
         }
+        if program_action == ProgramAction::Start {
+            self.last_started = Some(slot);
+        }
         Ok(())
     }
 
     /// Uploads a python program to the hub. Keep in mind that one slot can hold more than 1 program, but in order for a slot to be able to be ran, there has to be a program called "program.py."
-    pub async fn upload_program(&mut self, slot: u8, name: String, code: String) -> Result<()> {
-        let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
-        let mut crc32 = crc.digest();
-
-        crc32.update(code.as_bytes());
+    /// Names longer than [`MAX_PROGRAM_NAME_LEN`] bytes are rejected with [`Error::FilenameTooLong`], see [`validate_program_name`]. Returns an [`UploadReport`] for the transfer.
+    ///
+    /// Line endings are converted to the `\r\n` the hub expects before uploading, so `code` can use either.
+    pub async fn upload_program<S>(
+        &mut self,
+        slot: S,
+        name: String,
+        code: String,
+    ) -> Result<UploadReport>
+    where
+        S: TryInto<ProgramSlot>,
+        Error: From<S::Error>,
+    {
+        self.upload_program_inner(slot.try_into()?.get(), name, code, None)
+            .await
+    }
 
-        for _ in 0..((4 - (code.len() % 4)) % 4) {
-            crc32.update(&[0]);
+    /// Like [`SpikeConnection::upload_program`], but stops between chunks once `cancel` is cancelled, like when the user presses a cancel button.
+    ///
+    /// A cancelled upload leaves a partial file on the hub, so the slot is cleared before returning [`Error::Cancelled`].
+    /// This also removes any other programs in the slot. Returns [`Error::ClearFailed`] if the slot couldn't be cleared.
+    pub async fn upload_program_cancellable<S>(
+        &mut self,
+        slot: S,
+        name: String,
+        code: String,
+        cancel: CancellationToken,
+    ) -> Result<UploadReport>
+    where
+        S: TryInto<ProgramSlot>,
+        Error: From<S::Error>,
+    {
+        let slot = slot.try_into()?.get();
+        match self
+            .upload_program_inner(slot, name, code, Some(&cancel))
+            .await
+        {
+            Err(Error::Cancelled) => {
+                self.clear_for_upload(slot).await?;
+                Err(Error::Cancelled)
+            }
+            result => result,
         }
-        let crc32 = crc32.finalize();
+    }
+
+    async fn upload_program_inner(
+        &mut self,
+        slot: u8,
+        name: String,
+        code: String,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<UploadReport> {
+        validate_program_name(&name)?;
+        let code = to_crlf(&code);
+        let crc32 = self.file_crc32(code.as_bytes());
+        self.uploaded_crcs().remove(&slot);
+
         let message = StartFileUploadRequest {
             file_name: &name,
             program_slot: slot,
             crc32,
         };
-        self.send_message(message).await?;
-        let response = if let TxMessage::StartFileUploadResponse(r) = self.receive_message().await?
-        {
-            r.response_status
-        } else {
-            return Err(Error::WrongMessage);
-        };
-        if response == ResponseStatus::NotAcknowledged {
+        let response: StartFileUploadResponse = self.request(message).await?;
+        if response.response_status == ResponseStatus::NotAcknowledged {
             return Err(Error::NotAcknowledged("StartFileUploadRequest", None));
         }
-        self.send_chunks(code.into_bytes()).await?;
+        let report = self
+            .send_chunks_from(code.into_bytes(), 0, |_, _| {}, cancel)
+            .await?;
+        self.uploaded_crcs().insert(slot, (name, crc32));
 
-        Ok(())
+        Ok(report)
     }
 
-    /// Clears a program from a program slot.
-    pub async fn clear_program_slot(&mut self, slot: u8) -> Result<()> {
-        self.send_message(ClearSlotRequest { program_slot: slot })
-            .await?;
+    /// Uploads a python file to the hub with [`SpikeConnection::upload_program`], named after the file, like `program.py` for `src/program.py`.
+    /// Only a slot with a file named `program.py` can be started.
+    ///
+    /// Returns [`Error::FilenameTooLong`] if the file name is longer than [`MAX_PROGRAM_NAME_LEN`] bytes, instead of cutting it off and losing the extension.
+    pub async fn upload_file<S>(&mut self, slot: S, path: impl AsRef<Path>) -> Result<UploadReport>
+    where
+        S: TryInto<ProgramSlot>,
+        Error: From<S::Error>,
+    {
+        let path = path.as_ref();
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} isn't a file with a UTF-8 name", path.display()),
+                )
+            })?;
+        let code = std::fs::read_to_string(path)?;
+        self.upload_program(slot, name.to_string(), code).await
+    }
 
-        let status = if let TxMessage::ClearSlotResponse(r) = self.receive_message().await? {
-            r.response_status
-        } else {
-            return Err(Error::WrongMessage);
-        };
-        if status == ResponseStatus::NotAcknowledged {
-            return Err(Error::NotAcknowledged("ClearSlotResponse", None));
+    /// Uploads several python programs, given as `(slot, name, code)`, like a main program and the modules it uses.
+    /// Every slot that's uploaded to is cleared first, so programs sharing a slot are all kept. See [`SpikeConnection::upload_program`].
+    ///
+    /// Stops at the first program that fails, returning [`Error::UploadFailed`] with its slot and name, or [`Error::ClearFailed`] if its slot couldn't be cleared.
+    pub async fn upload_programs(&mut self, programs: &[(u8, String, String)]) -> Result<()> {
+        let mut slots: Vec<_> = programs.iter().map(|(slot, _, _)| *slot).collect();
+        slots.sort_unstable();
+        slots.dedup();
+        for slot in slots {
+            self.clear_for_upload(slot).await?;
+        }
+
+        for (slot, name, code) in programs {
+            self.upload_program(*slot, name.clone(), code.clone())
+                .await
+                .map_err(|e| upload_failed(*slot, name, e))?;
         }
         Ok(())
     }
 
-    fn encode_message(data: Vec<u8>) -> Vec<u8> {
-        const NO_DELIMITER: u8 = 0xff;
-        const DELIMITER: u8 = 0x02;
-        const MAX_BLOCK_SIZE: u8 = 84;
-        const COBS_CODE_OFFSET: u8 = 0x02;
+    /// Clears `slot`, uploads a python program to it, and starts it, which is what most programs that run code on the hub want to do.
+    /// Returns [`Error::ClearFailed`] if clearing the slot fails, [`Error::UploadFailed`] if uploading fails, or [`Error::StartFailed`] if the program doesn't start. A slot that was already empty is fine.
+    pub async fn deploy_and_run<S>(&mut self, slot: S, name: &str, code: &str) -> Result<()>
+    where
+        S: TryInto<ProgramSlot>,
+        Error: From<S::Error>,
+    {
+        let slot = slot.try_into()?.get();
+        self.clear_for_upload(slot).await?;
+        self.upload_program::<u8>(slot, name.to_string(), code.to_string())
+            .await
+            .map_err(|e| upload_failed(slot, name, e))?;
+        self.start_program::<u8>(slot)
+            .await
+            .map_err(|e| Error::StartFailed {
+                slot,
+                error: Box::new(e),
+            })
+    }
 
-        let mut buf = vec![NO_DELIMITER];
-        let mut code_index = 0;
-        let mut block = 1;
+    /// Uploads a python program to the hub with [`SpikeConnection::upload_program`], unless it's the same as the last program uploaded to that slot, by name and contents.
+    /// Returns whether the program was actually uploaded.
+    ///
+    /// The uploaded programs are only tracked per connection, so the cache starts out empty after reconnecting, and programs uploaded by other means aren't known about.
+    pub async fn upload_if_changed<S>(
+        &mut self,
+        slot: S,
+        name: String,
+        code: String,
+    ) -> Result<bool>
+    where
+        S: TryInto<ProgramSlot>,
+        Error: From<S::Error>,
+    {
+        let slot = slot.try_into()?.get();
+        let crc32 = self.file_crc32(to_crlf(&code).as_bytes());
+        if self.uploaded_crcs().get(&slot) == Some(&(name.clone(), crc32)) {
+            return Ok(false);
+        }
 
-        for byte in data {
-            if byte > DELIMITER {
-                buf.push(byte);
-                block += 1;
-            }
+        self.upload_program::<u8>(slot, name, code).await?;
+        Ok(true)
+    }
 
-            if byte <= DELIMITER || block > MAX_BLOCK_SIZE {
-                if byte <= DELIMITER {
-                    let delimiter_base = byte * MAX_BLOCK_SIZE;
-                    let block_offset = block + COBS_CODE_OFFSET;
-                    buf[code_index] = delimiter_base + block_offset;
-                }
+    /// Flashes a new firmware image to the hub.
+    ///
+    /// If the hub already has part of the same image from an interrupted update, only the rest of it is sent.
+    /// Once the whole image is uploaded, the hub is told to install it, and will restart.
+    pub async fn update_firmware(&mut self, firmware: Vec<u8>) -> Result<()> {
+        use sha1::{Digest, Sha1};
 
-                code_index = buf.len();
-                buf.push(NO_DELIMITER);
-                block = 1;
-            }
+        let file_sha: [u8; 20] = Sha1::digest(&firmware).into();
+        let crc32 = self.file_crc32(&firmware);
+
+        let response: StartFirmwareUploadResponse = self
+            .request(StartFirmwareUploadRequest { file_sha, crc32 })
+            .await?;
+        if response.response_status == ResponseStatus::NotAcknowledged {
+            return Err(Error::NotAcknowledged("StartFirmwareUploadRequest", None));
         }
 
-        buf[code_index] = block + COBS_CODE_OFFSET;
-        buf.iter_mut().for_each(|x| *x ^= 0x03);
-        buf.push(0x02);
+        self.send_chunks_from(
+            firmware,
+            response.already_uploaded as usize,
+            |_, _| {},
+            None,
+        )
+        .await?;
 
-        buf
+        let response: BeginFirmwareUpdateResponse = self
+            .request(BeginFirmwareUpdateRequest { file_sha, crc32 })
+            .await?;
+        if response.response_status == ResponseStatus::NotAcknowledged {
+            return Err(Error::NotAcknowledged("BeginFirmwareUpdateRequest", None));
+        }
+        Ok(())
     }
 
-    fn decode_message(mut data: Vec<u8>) -> Vec<u8> {
-        let mut start = 0;
-        if data[0] == 0x01 {
-            start += 1;
+    /// Clears `slot` with [`SpikeConnection::clear_program_slot`], returning [`Error::ClearFailed`] if it fails. A slot that was already empty is fine.
+    async fn clear_for_upload(&mut self, slot: u8) -> Result<()> {
+        match self.clear_program_slot::<u8>(slot).await {
+            // The slot was already empty
+            Ok(()) | Err(Error::NotAcknowledged(..)) => Ok(()),
+            Err(e) => Err(Error::ClearFailed {
+                slot,
+                error: Box::new(e),
+            }),
         }
+    }
 
-        let neg_one = data.len() - 1;
-        data[start..neg_one].iter_mut().for_each(|x| *x ^= 0x03);
-
-        let mut buf = Vec::new();
-
-        let (mut value, mut block) = Self::unescape(data[0]);
-        for byte in &data[1..] {
-            block -= 1;
-            if block > 0 {
-                buf.push(*byte);
-                continue;
-            }
+    /// Clears a program from a program slot.
+    pub async fn clear_program_slot<S>(&mut self, slot: S) -> Result<()>
+    where
+        S: TryInto<ProgramSlot>,
+        Error: From<S::Error>,
+    {
+        let slot = slot.try_into()?.get();
+        let response: ClearSlotResponse = self
+            .request(ClearSlotRequest { program_slot: slot })
+            .await?;
+        if response.response_status == ResponseStatus::NotAcknowledged {
+            return Err(Error::NotAcknowledged("ClearSlotResponse", None));
+        }
+        self.uploaded_crcs().remove(&slot);
+        Ok(())
+    }
 
-            if let Some(val) = value {
-                buf.push(val)
-            }
+    /// Would read a program back off of the hub, returning its name and contents.
+    ///
+    /// The protocol only has messages for uploading files, not downloading them, so this always returns [`Error::Unsupported`] for a valid slot.
+    /// Keep a copy of programs when uploading them if they need to be backed up.
+    pub async fn download_program<S>(&mut self, slot: S) -> Result<(String, Vec<u8>)>
+    where
+        S: TryInto<ProgramSlot>,
+        Error: From<S::Error>,
+    {
+        slot.try_into()?;
+        Err(Error::Unsupported)
+    }
 
-            (value, block) = Self::unescape(*byte);
+    /// Checks that a [`TransferChunkRequest`] isn't bigger than the hub accepts, since its payload would be cut off otherwise.
+    fn check_chunk_size(message: &RxMessage, max_chunk_size: usize) -> Result<()> {
+        if let RxMessage::TransferChunkRequest(r) = message
+            && r.payload.len() > max_chunk_size
+        {
+            return Err(Error::OversizedMessage);
         }
+        Ok(())
+    }
 
-        if buf.pop() != Some(0) {
-            // Remove last 0
-            panic!("removed something bad: {buf:?}");
+    /// Encodes a message, checking that the encoded frame fits in `max_message_size`.
+    /// The check is on the encoded frame, since encoding adds a byte for every block and the delimiter, and the frame is what the hub has to buffer.
+    fn encode_checked(message: Vec<u8>, max_message_size: usize) -> Result<Vec<u8>> {
+        let bytes = cobs::encode(&message);
+        if bytes.len() > max_message_size {
+            return Err(Error::OversizedMessage);
         }
-        buf
+        Ok(bytes)
     }
+}
 
-    fn unescape(code: u8) -> (Option<u8>, u8) {
-        const MAX_BLOCK_SIZE: u8 = 84;
-        const COBS_CODE_OFFSET: u8 = 0x02;
+/// Where [`filter_thread`] sends each kind of message it receives.
+struct FilterChannels {
+    msg_tx: Sender<Result<TxMessage>>,
+    device_notification: Arc<Mutex<Option<DeviceNotification>>>,
+    /// Where to also send device notifications, if [`SpikeConnection::device_notification_stream`] was called.
+    device_notification_tx: Arc<Mutex<Option<Sender<DeviceNotification>>>>,
+    console_tx: Sender<ConsoleNotification>,
+    program_flow_tx: Sender<ProgramFlowNotification>,
+    /// Where to send console and program flow notifications instead, if [`SpikeConnection::program_event_stream`] was called.
+    program_event_tx: Arc<Mutex<Option<Sender<ProgramEvent>>>>,
+    error_tx: Sender<Error>,
+    lifecycle_tx: Sender<LifecycleEvent>,
+    battery_thresholds: Arc<Mutex<BatteryThresholds>>,
+    /// Notified whenever a notification is received.
+    notification_activity: Arc<Notify>,
+    trace: Arc<std::sync::Mutex<Option<TraceFn>>>,
+    /// Where to also send decoded frames, if [`SpikeConnection::receive_raw`] was called.
+    raw_tx: Arc<Mutex<Option<Sender<Vec<u8>>>>>,
+    ignore_unknown: Arc<AtomicBool>,
+    unknown_messages: Arc<AtomicUsize>,
+    dropped_console: Arc<AtomicUsize>,
+    dropped_program_flow: Arc<AtomicUsize>,
+    connected: Arc<AtomicBool>,
+}
 
-        if code == 0xff {
-            return (None, MAX_BLOCK_SIZE + 1);
+impl FilterChannels {
+    /// Sends a device notification to the device notification stream, if there is one.
+    async fn send_device_notification(&self, notification: &DeviceNotification) {
+        let Some(sender) = self.device_notification_tx.lock().await.clone() else {
+            return;
+        };
+        // A slow reader only misses notifications, since the latest one is always kept too
+        if let Err(TrySendError::Closed(_)) = sender.try_send(notification.clone()) {
+            // The receiver was dropped, so stop sending to it.
+            *self.device_notification_tx.lock().await = None;
         }
+    }
 
-        let mut value = (code - COBS_CODE_OFFSET) / MAX_BLOCK_SIZE;
-        let mut block = (code - COBS_CODE_OFFSET) % MAX_BLOCK_SIZE;
+    async fn send_raw_frame(&self, frame: &[u8]) {
+        let mut raw_tx = self.raw_tx.lock().await;
+        let Some(sender) = raw_tx.as_ref() else {
+            return;
+        };
+        // Nobody might be reading raw frames, so drop them instead of waiting
+        if let Err(TrySendError::Closed(_)) = sender.try_send(frame.to_vec()) {
+            *raw_tx = None;
+        }
+    }
 
-        if block == 0 {
-            block = MAX_BLOCK_SIZE;
-            value = value.wrapping_sub(1);
+    /// Sends an event to the program event stream, or returns it if there isn't one.
+    /// Events are dropped if the stream is full, like the separate channels, so they can't hold up responses.
+    async fn send_program_event(&self, event: ProgramEvent) -> Option<ProgramEvent> {
+        let Some(sender) = self.program_event_tx.lock().await.clone() else {
+            return Some(event);
+        };
+        match sender.try_send(event) {
+            Ok(()) => None,
+            Err(TrySendError::Full(ProgramEvent::Console(_))) => {
+                self.dropped_console.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            Err(TrySendError::Full(ProgramEvent::ProgramFlow(_))) => {
+                self.dropped_program_flow.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            Err(TrySendError::Closed(event)) => {
+                // The receiver was dropped, so go back to the separate channels.
+                *self.program_event_tx.lock().await = None;
+                Some(event)
+            }
         }
+    }
+}
 
-        (Some(value), block)
+/// The receiving ends of the channels [`filter_thread`] sends to.
+struct FilterReceivers {
+    msg_rx: Receiver<Result<TxMessage>>,
+    console_rx: Receiver<ConsoleNotification>,
+    program_flow_rx: Receiver<ProgramFlowNotification>,
+    error_rx: Receiver<Error>,
+    lifecycle_rx: Receiver<LifecycleEvent>,
+    handle: JoinHandle<()>,
+}
+
+/// The state [`filter_thread`] shares with [`SpikeConnection`], which is kept when reconnecting.
+struct FilterShared {
+    device_notification: Arc<Mutex<Option<DeviceNotification>>>,
+    device_notification_tx: Arc<Mutex<Option<Sender<DeviceNotification>>>>,
+    program_event_tx: Arc<Mutex<Option<Sender<ProgramEvent>>>>,
+    battery_thresholds: Arc<Mutex<BatteryThresholds>>,
+    notification_activity: Arc<Notify>,
+    max_frame_size: Arc<AtomicUsize>,
+    trace: Arc<std::sync::Mutex<Option<TraceFn>>>,
+    raw_tx: Arc<Mutex<Option<Sender<Vec<u8>>>>>,
+    ignore_unknown: Arc<AtomicBool>,
+    unknown_messages: Arc<AtomicUsize>,
+    dropped_console: Arc<AtomicUsize>,
+    dropped_program_flow: Arc<AtomicUsize>,
+    connected: Arc<AtomicBool>,
+    config: SpikeConnectionConfig,
+}
+
+/// Starts [`filter_thread`] with new channels.
+fn spawn_filter_thread(notifications: ByteStream, shared: FilterShared) -> FilterReceivers {
+    let FilterShared {
+        device_notification,
+        device_notification_tx,
+        program_event_tx,
+        battery_thresholds,
+        notification_activity,
+        max_frame_size,
+        trace,
+        raw_tx,
+        ignore_unknown,
+        unknown_messages,
+        dropped_console,
+        dropped_program_flow,
+        connected,
+        config,
+    } = shared;
+    let (msg_tx, msg_rx) = mpsc::channel(config.message_capacity.max(1));
+    let (console_tx, console_rx) = mpsc::channel(config.console_capacity.max(1));
+    let (program_flow_tx, program_flow_rx) = mpsc::channel(config.program_flow_capacity.max(1));
+    let (error_tx, error_rx) = mpsc::channel(config.error_capacity.max(1));
+    let (lifecycle_tx, lifecycle_rx) = mpsc::channel(4);
+
+    let handle = tokio::spawn(filter_thread(
+        notifications,
+        FilterChannels {
+            msg_tx,
+            device_notification,
+            device_notification_tx,
+            console_tx,
+            program_flow_tx,
+            program_event_tx,
+            error_tx,
+            lifecycle_tx,
+            battery_thresholds,
+            notification_activity,
+            trace,
+            raw_tx,
+            ignore_unknown,
+            unknown_messages,
+            dropped_console,
+            dropped_program_flow,
+            connected,
+        },
+        max_frame_size,
+    ));
+
+    FilterReceivers {
+        msg_rx,
+        console_rx,
+        program_flow_rx,
+        error_rx,
+        lifecycle_rx,
+        handle,
     }
 }
 
 async fn filter_thread(
-    msg_tx: Sender<Result<TxMessage>>,
-    device_notification: Arc<Mutex<Option<DeviceNotification>>>,
-    mut notifications: Pin<Box<dyn Stream<Item = ValueNotification> + Send>>,
-    console_tx: Sender<ConsoleNotification>,
-    program_flow_tx: Sender<ProgramFlowNotification>,
+    mut notifications: ByteStream,
+    channels: FilterChannels,
+    max_frame_size: Arc<AtomicUsize>,
 ) {
-    let mut buffer = Vec::new();
+    let mut frames = FrameBuffer::new(max_frame_size);
+    let mut decoded = Vec::new();
 
     loop {
-        let mut x = notifications.next().await.unwrap();
-        buffer.append(&mut x.value);
-        if buffer.ends_with(&[0x02]) {
-            let decode_buffer = SpikeConnection::decode_message(buffer);
-            let message = TxMessage::deserialize(decode_buffer);
-            buffer = Vec::new();
-
-            if let Ok(TxMessage::DeviceNotification(r)) = message {
-                *device_notification.lock().await = Some(r);
-            } else if let Ok(TxMessage::ConsoleNotification(r)) = message {
-                console_tx.send(r).await.expect("BUG");
-            } else if let Ok(TxMessage::ProgramFlowNotification(r)) = message {
-                program_flow_tx.send(r).await.expect("BUG");
-            } else {
-                msg_tx.send(message).await.expect("BUG");
+        let Some(x) = notifications.next().await else {
+            // The hub disconnected. If the channel is full, the receiver will still see it closed once it's drained.
+            channels.connected.store(false, Ordering::Relaxed);
+            let _ = channels.msg_tx.try_send(Err(Error::Disconnected));
+            channels.raw_tx.lock().await.take();
+            return;
+        };
+        trace(&channels.trace, Direction::Tx, &x);
+        frames.push(&x);
+        while let Some(frame) = frames.next_frame() {
+            let frame = frame.and_then(|frame| cobs::decode_into(frame, &mut decoded));
+            if frame.is_ok() {
+                channels.send_raw_frame(&decoded).await;
+            }
+            let message = frame.and_then(|_| TxMessage::deserialize_slice(&decoded));
+
+            match message {
+                Ok(TxMessage::DeviceNotification(r)) => {
+                    if let Some(battery) = r.battery() {
+                        let crossed = channels.battery_thresholds.lock().await.update(battery);
+                        for threshold in crossed {
+                            let _ = channels
+                                .lifecycle_tx
+                                .try_send(LifecycleEvent::BatteryThresholdCrossed(threshold));
+                        }
+                    }
+                    channels.send_device_notification(&r).await;
+                    *channels.device_notification.lock().await = Some(r);
+                    channels.notification_activity.notify_waiters();
+                }
+                Ok(TxMessage::ConsoleNotification(r)) => {
+                    let event = channels.send_program_event(ProgramEvent::Console(r)).await;
+                    if let Some(ProgramEvent::Console(r)) = event
+                        && let Err(TrySendError::Full(_)) = channels.console_tx.try_send(r)
+                    {
+                        // Nobody might be reading the console, which shouldn't hold up responses
+                        channels.dropped_console.fetch_add(1, Ordering::Relaxed);
+                    }
+                    channels.notification_activity.notify_waiters();
+                }
+                Ok(TxMessage::ProgramFlowNotification(r)) => {
+                    let event = channels
+                        .send_program_event(ProgramEvent::ProgramFlow(r))
+                        .await;
+                    if let Some(ProgramEvent::ProgramFlow(r)) = event
+                        && let Err(TrySendError::Full(_)) = channels.program_flow_tx.try_send(r)
+                    {
+                        channels
+                            .dropped_program_flow
+                            .fetch_add(1, Ordering::Relaxed);
+                    }
+                    channels.notification_activity.notify_waiters();
+                }
+                // Unknown devices are kept in their notifications, so only whole messages of unknown types get here
+                Ok(TxMessage::Unknown { id, .. }) => {
+                    channels.unknown_messages.fetch_add(1, Ordering::Relaxed);
+                    if !channels.ignore_unknown.load(Ordering::Relaxed) {
+                        let _ = channels.error_tx.try_send(Error::UnknownMessage(id));
+                    }
+                }
+                Ok(message) => {
+                    channels.msg_tx.send(Ok(message)).await.expect("BUG");
+                }
+                Err(e) => {
+                    // Nobody might be reading errors, so don't block waiting for them to be.
+                    let _ = channels.error_tx.try_send(e);
+                }
             }
         }
     }
 }
+
+/// Splits the bytes of notifications into frames on their delimiters. A frame can be split across several notifications, and a notification can hold several frames.
+///
+/// The delimiter never shows up inside an encoded frame, so it always marks where a frame ends.
+struct FrameBuffer {
+    buffer: Vec<u8>,
+    /// Where the next frame starts in the buffer. Everything before it was already returned, and is removed on the next push.
+    start: usize,
+    max_len: Arc<AtomicUsize>,
+    /// Whether the rest of a frame that was too big is still being skipped.
+    discarding: bool,
+}
+
+impl FrameBuffer {
+    fn new(max_len: Arc<AtomicUsize>) -> Self {
+        Self {
+            buffer: Vec::new(),
+            start: 0,
+            max_len,
+            discarding: false,
+        }
+    }
+
+    /// Adds received bytes. Call [`FrameBuffer::next_frame`] until it returns None to get the frames they complete.
+    fn push(&mut self, bytes: &[u8]) {
+        // Reuse the buffer instead of reallocating it for every frame
+        self.buffer.drain(..self.start);
+        self.start = 0;
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Returns the next complete frame, including its delimiter, or None if there isn't one yet.
+    /// If a frame gets bigger than the maximum without ending, like when its delimiter was lost, this returns [`Error::MalformedFrame`] and skips ahead to the next frame.
+    fn next_frame(&mut self) -> Option<Result<&[u8]>> {
+        loop {
+            let rest = &self.buffer[self.start..];
+            let Some(end) = rest.iter().position(|b| *b == cobs::DELIMITER) else {
+                if self.discarding {
+                    self.buffer.truncate(self.start);
+                } else if rest.len() > self.max_len.load(Ordering::Relaxed) {
+                    self.buffer.truncate(self.start);
+                    self.discarding = true;
+                    return Some(Err(Error::MalformedFrame));
+                }
+                return None;
+            };
+
+            let frame_start = self.start;
+            self.start += end + 1;
+            if self.discarding {
+                // This was the end of the frame that was too big
+                self.discarding = false;
+                continue;
+            }
+            return Some(Ok(&self.buffer[frame_start..self.start]));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unterminated_frame() {
+        let mut frames = FrameBuffer::new(Arc::new(AtomicUsize::new(16)));
+        frames.push(&[0xaa; 10]);
+        assert!(frames.next_frame().is_none());
+        frames.push(&[0xaa; 10]);
+        assert!(matches!(
+            frames.next_frame(),
+            Some(Err(Error::MalformedFrame))
+        ));
+
+        // The rest of the bad frame is skipped, and the next one is received
+        frames.push(&[0xaa; 10]);
+        assert!(frames.next_frame().is_none());
+        let frame = cobs::encode(&[0x01, 0x02, 0x03]);
+        let mut bytes = vec![0xaa, 0x02];
+        bytes.extend_from_slice(&frame);
+        frames.push(&bytes);
+        assert_eq!(frames.next_frame().unwrap().unwrap(), frame);
+
+        // The buffer is reused for the next frame
+        frames.push(&frame);
+        assert_eq!(frames.next_frame().unwrap().unwrap(), frame);
+        assert!(frames.next_frame().is_none());
+    }
+
+    #[test]
+    fn split_frame() {
+        let mut frames = FrameBuffer::new(Arc::new(AtomicUsize::new(1024)));
+        let frame = cobs::encode(&[0x21, b'h', b'i', 0x00]);
+        for byte in &frame[..frame.len() - 1] {
+            frames.push(&[*byte]);
+            assert!(frames.next_frame().is_none());
+        }
+        frames.push(&frame[frame.len() - 1..]);
+        assert_eq!(frames.next_frame().unwrap().unwrap(), frame);
+        assert!(frames.next_frame().is_none());
+    }
+
+    #[test]
+    fn coalesced_frames() {
+        let mut frames = FrameBuffer::new(Arc::new(AtomicUsize::new(1024)));
+        let first = cobs::encode(&[0x19, b'a', 0x00]);
+        let second = cobs::encode(&[0x01, 0x02, 0x03]);
+        let third = cobs::encode(&[0x1f, 0x00]);
+
+        // Two whole frames and the start of a third in one notification
+        let mut bytes = [first.clone(), second.clone()].concat();
+        bytes.extend_from_slice(&third[..2]);
+        frames.push(&bytes);
+        assert_eq!(frames.next_frame().unwrap().unwrap(), first);
+        assert_eq!(frames.next_frame().unwrap().unwrap(), second);
+        assert!(frames.next_frame().is_none());
+
+        frames.push(&third[2..]);
+        assert_eq!(frames.next_frame().unwrap().unwrap(), third);
+        assert!(frames.next_frame().is_none());
+    }
+
+    #[test]
+    fn padded_crc() {
+        assert_eq!(padded_crc32(b"1234"), 0x9be3e0a3);
+        assert_eq!(padded_crc32(b"123456789"), padded_crc32(b"123456789\0\0\0"));
+        assert_eq!(padded_crc32(b"123456789"), 0x77d55834);
+        assert_eq!(padded_crc32(b""), 0);
+    }
+
+    #[test]
+    fn chunked_crc() {
+        let data: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+        for chunk_size in [4, 8, 100, 512, 1000] {
+            let mut crc = ChunkedCrc::new();
+            for chunk in data.chunks(chunk_size) {
+                crc.update(chunk);
+            }
+            assert_eq!(crc.value(), padded_crc32(&data));
+        }
+
+        // The last chunk is padded
+        let mut crc = ChunkedCrc::new();
+        crc.update(b"1234");
+        assert_eq!(crc.update(b"56789"), padded_crc32(b"123456789"));
+
+        assert_eq!(ChunkedCrc::new().value(), padded_crc32(b""));
+
+        // Algorithms that aren't reflected carry on between chunks too
+        let bzip2 = crc::Crc::<u32>::new(&crc::CRC_32_BZIP2);
+        let mut crc = ChunkedCrc::with_algorithm(&crc::CRC_32_BZIP2);
+        crc.update(b"1234");
+        assert_eq!(crc.update(b"56789"), bzip2.checksum(b"123456789\0\0\0"));
+    }
+
+    #[test]
+    fn oversized_chunk() {
+        let payload = vec![0; 70000];
+        let message = RxMessage::from(TransferChunkRequest {
+            crc32: 0,
+            payload: &payload,
+        });
+        assert!(matches!(
+            SpikeConnection::check_chunk_size(&message, 512),
+            Err(Error::OversizedMessage)
+        ));
+
+        let message = RxMessage::from(TransferChunkRequest {
+            crc32: 0,
+            payload: &payload[..512],
+        });
+        assert!(SpikeConnection::check_chunk_size(&message, 512).is_ok());
+    }
+
+    #[test]
+    fn battery_threshold_debounced() {
+        let mut thresholds = BatteryThresholds::new(&[20]);
+        let crossed: Vec<_> = [21, 20, 21, 20, 23, 19]
+            .into_iter()
+            .map(|battery| thresholds.update(battery))
+            .collect();
+        // Hovering around 20 only counts once, until the battery goes back above it by the hysteresis
+        assert_eq!(
+            crossed,
+            [vec![], vec![20], vec![], vec![], vec![], vec![20]]
+        );
+    }
+
+    #[test]
+    fn battery_thresholds_crossed_at_once() {
+        let mut thresholds = BatteryThresholds::new(&[5, 20, 10, 20]);
+        assert!(thresholds.update(50).is_empty());
+        assert_eq!(thresholds.update(4), [20, 10, 5]);
+        assert!(thresholds.update(3).is_empty());
+    }
+
+    #[test]
+    fn near_max_message_fits() {
+        let max_message_size = 512;
+        let max_packet_size = 20;
+
+        // Every 84 bytes needs a code byte, plus one for the last block and the delimiter, so 504 bytes encode to exactly 512
+        let message = vec![0xaa; 504];
+        let bytes = SpikeConnection::encode_checked(message.clone(), max_message_size).unwrap();
+        assert_eq!(bytes.len(), max_message_size);
+
+        let packets: Vec<_> = bytes.chunks(max_packet_size).collect();
+        assert!(packets.iter().all(|p| p.len() <= max_packet_size));
+        assert_eq!(packets.concat(), bytes);
+        assert_eq!(cobs::decode(&packets.concat()).unwrap(), message);
+
+        let too_big = vec![0xaa; 505];
+        assert!(matches!(
+            SpikeConnection::encode_checked(too_big, max_message_size),
+            Err(Error::OversizedMessage)
+        ));
+    }
+}
@@ -0,0 +1,128 @@
+//! The framing used by the SPIKE Prime protocol, which is useful for talking to the hub over other transports, like its USB serial port.
+//!
+//! Messages are framed with a variant of [COBS](https://en.wikipedia.org/wiki/Consistent_Overhead_Byte_Stuffing) that removes the bytes 0x00, 0x01, and 0x02 instead of just 0x00.
+//! A frame is made of blocks of up to [`MAX_BLOCK_SIZE`] bytes, each starting with a code byte. The code byte says how long the block is, and which of the removed bytes ends it:
+//! `removed_byte * MAX_BLOCK_SIZE + block_length + COBS_CODE_OFFSET`, where the length includes the code byte. A code of [`NO_DELIMITER`] means the block is full and nothing was removed after it.
+//!
+//! Every byte of the frame is then XOR'd with [`XOR`], and the frame ends with [`DELIMITER`].
+//! Frames from the hub can also start with [`HIGH_PRIORITY`], which isn't part of the encoded message.
+
+use crate::error::*;
+
+/// The byte that ends every frame.
+pub const DELIMITER: u8 = 0x02;
+/// The code byte of a full block, that isn't followed by a removed byte.
+pub const NO_DELIMITER: u8 = 0xff;
+/// The longest a block can be, including its code byte.
+pub const MAX_BLOCK_SIZE: u8 = 84;
+/// Added to each code byte, so that code bytes are never one of the removed bytes.
+pub const COBS_CODE_OFFSET: u8 = 0x02;
+/// Every byte of the frame except the delimiter is XOR'd with this.
+pub const XOR: u8 = 0x03;
+/// The byte that high priority frames from the hub start with.
+pub const HIGH_PRIORITY: u8 = 0x01;
+
+/// Encodes a message into a frame, including the delimiter.
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let mut buf = vec![NO_DELIMITER];
+    let mut code_index = 0;
+    let mut block = 1;
+
+    for &byte in data {
+        if byte > DELIMITER {
+            buf.push(byte);
+            block += 1;
+        }
+
+        if byte <= DELIMITER || block > MAX_BLOCK_SIZE {
+            if byte <= DELIMITER {
+                let delimiter_base = byte * MAX_BLOCK_SIZE;
+                let block_offset = block + COBS_CODE_OFFSET;
+                buf[code_index] = delimiter_base + block_offset;
+            }
+
+            code_index = buf.len();
+            buf.push(NO_DELIMITER);
+            block = 1;
+        }
+    }
+
+    buf[code_index] = block + COBS_CODE_OFFSET;
+    buf.iter_mut().for_each(|x| *x ^= XOR);
+    buf.push(DELIMITER);
+
+    buf
+}
+
+/// Decodes a frame back into a message. The frame must include its delimiter, and can start with [`HIGH_PRIORITY`].
+///
+/// Returns [`Error::MalformedFrame`] if the frame isn't valid, like when part of it was lost.
+pub fn decode(data: &[u8]) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    decode_into(data, &mut buf)?;
+    Ok(buf)
+}
+
+/// Decodes a frame into `buf`, replacing what was in it, so the same buffer can be reused for every frame.
+pub(crate) fn decode_into(data: &[u8], buf: &mut Vec<u8>) -> Result<()> {
+    buf.clear();
+
+    let start = if data.first() == Some(&HIGH_PRIORITY) {
+        1
+    } else {
+        0
+    };
+    if data.len() < start + 2 || data.last() != Some(&DELIMITER) {
+        return Err(Error::MalformedFrame);
+    }
+    let mut frame = data[start..data.len() - 1].iter().map(|x| x ^ XOR);
+
+    let (mut value, mut block) = unescape(frame.next().expect("BUG"))?;
+    for byte in frame {
+        block -= 1;
+        if block > 0 {
+            buf.push(byte);
+            continue;
+        }
+
+        if let Some(val) = value {
+            buf.push(val)
+        }
+
+        (value, block) = unescape(byte)?;
+    }
+
+    // The delimiter should end the last block
+    if block != 1 {
+        return Err(Error::MalformedFrame);
+    }
+    if let Some(val) = value {
+        buf.push(val)
+    }
+
+    // Remove last 0
+    if buf.pop() != Some(0) {
+        return Err(Error::MalformedFrame);
+    }
+    Ok(())
+}
+
+/// Splits a code byte into the byte that was removed after its block, if any, and the length of the block.
+fn unescape(code: u8) -> Result<(Option<u8>, u8)> {
+    if code == NO_DELIMITER {
+        return Ok((None, MAX_BLOCK_SIZE + 1));
+    }
+    if code < COBS_CODE_OFFSET {
+        return Err(Error::MalformedFrame);
+    }
+
+    let mut value = (code - COBS_CODE_OFFSET) / MAX_BLOCK_SIZE;
+    let mut block = (code - COBS_CODE_OFFSET) % MAX_BLOCK_SIZE;
+
+    if block == 0 {
+        block = MAX_BLOCK_SIZE;
+        value = value.wrapping_sub(1);
+    }
+
+    Ok((Some(value), block))
+}
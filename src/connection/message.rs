@@ -1,15 +1,24 @@
 //! Module for messages that can be sent to the SPIKE Prime, and received from the SPIKE Prime.
 
-use std::io::{Cursor, Read};
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Cursor, Read},
+};
 
 use byteorder::{LittleEndian, ReadBytesExt};
 use from_variants::FromVariants;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::error::*;
 
 /// Messages sent to the SPIKE Prime
+///
+/// Like the other enums for messages and devices, this is `#[non_exhaustive]`, so that support for new messages can be added without breaking code that matches on it.
 #[derive(Debug, PartialEq, Eq, Hash, Clone, FromVariants)]
+#[non_exhaustive]
 pub enum RxMessage<'a> {
     InfoRequest,
     StartFirmwareUploadRequest(StartFirmwareUploadRequest),
@@ -44,7 +53,111 @@ impl<'a> RxMessage<'a> {
     }
 }
 
+/// An [`RxMessage`] that owns its strings and bytes, so it can be kept around, like for recording the messages sent to a hub and replaying them later.
+/// With the `serde` feature, this can be serialized.
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
+pub enum OwnedRxMessage {
+    InfoRequest,
+    StartFirmwareUploadRequest(StartFirmwareUploadRequest),
+    StartFileUploadRequest {
+        file_name: String,
+        program_slot: u8,
+        crc32: u32,
+    },
+    TransferChunkRequest {
+        crc32: u32,
+        payload: Vec<u8>,
+    },
+    BeginFirmwareUpdateRequest(BeginFirmwareUpdateRequest),
+    SetHubNameRequest {
+        name: String,
+    },
+    GetHubNameRequest,
+    DeviceUuidRequest,
+    ProgramFlowRequest(ProgramFlowRequest),
+    ClearSlotRequest(ClearSlotRequest),
+    TunnelMessage {
+        payload: Vec<u8>,
+    },
+    DeviceNotificationRequest(DeviceNotificationRequest),
+}
+
+impl OwnedRxMessage {
+    /// Borrows the message as an [`RxMessage`], which can be sent with [`SpikeConnection::send_message`](crate::connection::SpikeConnection::send_message).
+    pub fn as_message(&self) -> RxMessage<'_> {
+        match self {
+            OwnedRxMessage::InfoRequest => RxMessage::InfoRequest,
+            OwnedRxMessage::StartFirmwareUploadRequest(r) => r.clone().into(),
+            OwnedRxMessage::StartFileUploadRequest {
+                file_name,
+                program_slot,
+                crc32,
+            } => StartFileUploadRequest {
+                file_name,
+                program_slot: *program_slot,
+                crc32: *crc32,
+            }
+            .into(),
+            OwnedRxMessage::TransferChunkRequest { crc32, payload } => TransferChunkRequest {
+                crc32: *crc32,
+                payload,
+            }
+            .into(),
+            OwnedRxMessage::BeginFirmwareUpdateRequest(r) => r.clone().into(),
+            OwnedRxMessage::SetHubNameRequest { name } => SetHubNameRequest { name }.into(),
+            OwnedRxMessage::GetHubNameRequest => RxMessage::GetHubNameRequest,
+            OwnedRxMessage::DeviceUuidRequest => RxMessage::DeviceUuidRequest,
+            OwnedRxMessage::ProgramFlowRequest(r) => r.clone().into(),
+            OwnedRxMessage::ClearSlotRequest(r) => r.clone().into(),
+            OwnedRxMessage::TunnelMessage { payload } => TunnelMessage { payload }.into(),
+            OwnedRxMessage::DeviceNotificationRequest(r) => r.clone().into(),
+        }
+    }
+
+    /// Serializes the message into the bytes sent to the hub, like [`RxMessage::serialize`].
+    pub fn serialize(&self) -> Vec<u8> {
+        self.as_message().serialize()
+    }
+}
+
+impl From<RxMessage<'_>> for OwnedRxMessage {
+    fn from(message: RxMessage<'_>) -> Self {
+        match message {
+            RxMessage::InfoRequest => OwnedRxMessage::InfoRequest,
+            RxMessage::StartFirmwareUploadRequest(r) => {
+                OwnedRxMessage::StartFirmwareUploadRequest(r)
+            }
+            RxMessage::StartFileUploadRequest(r) => OwnedRxMessage::StartFileUploadRequest {
+                file_name: r.file_name.to_string(),
+                program_slot: r.program_slot,
+                crc32: r.crc32,
+            },
+            RxMessage::TransferChunkRequest(r) => OwnedRxMessage::TransferChunkRequest {
+                crc32: r.crc32,
+                payload: r.payload.to_vec(),
+            },
+            RxMessage::BeginFirmwareUpdateRequest(r) => {
+                OwnedRxMessage::BeginFirmwareUpdateRequest(r)
+            }
+            RxMessage::SetHubNameRequest(r) => OwnedRxMessage::SetHubNameRequest {
+                name: r.name.to_string(),
+            },
+            RxMessage::GetHubNameRequest => OwnedRxMessage::GetHubNameRequest,
+            RxMessage::DeviceUuidRequest => OwnedRxMessage::DeviceUuidRequest,
+            RxMessage::ProgramFlowRequest(r) => OwnedRxMessage::ProgramFlowRequest(r),
+            RxMessage::ClearSlotRequest(r) => OwnedRxMessage::ClearSlotRequest(r),
+            RxMessage::TunnelMessage(r) => OwnedRxMessage::TunnelMessage {
+                payload: r.payload.to_vec(),
+            },
+            RxMessage::DeviceNotificationRequest(r) => OwnedRxMessage::DeviceNotificationRequest(r),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct StartFirmwareUploadRequest {
     pub file_sha: [u8; 20],
     pub crc32: u32,
@@ -71,7 +184,7 @@ impl<'a> StartFileUploadRequest<'a> {
     pub fn serialize(self) -> Vec<u8> {
         let mut buf = Vec::new();
         buf.push(0x0c); // ID
-        buf.extend_from_slice(&self.file_name.as_bytes()[..31.min(self.file_name.len())]);
+        buf.extend_from_slice(truncate_str(self.file_name, MAX_PROGRAM_NAME_LEN).as_bytes());
         buf.push(0x00); // null-terminator
         buf.push(self.program_slot);
         buf.extend_from_slice(&self.crc32.to_le_bytes());
@@ -79,6 +192,49 @@ impl<'a> StartFileUploadRequest<'a> {
     }
 }
 
+/// The longest file name the hub will store, in bytes. See [`effective_program_name`].
+pub const MAX_PROGRAM_NAME_LEN: usize = 31;
+
+/// Returns the name the hub will actually store a program as, since names are cut off after [`MAX_PROGRAM_NAME_LEN`] bytes, and whether it was cut off.
+/// Names are only cut off between characters, so this can be used to preview the name before uploading, and warn about it. To reject long names instead, see [`validate_program_name`].
+pub fn effective_program_name(name: &str) -> (String, bool) {
+    let effective = truncate_str(name, MAX_PROGRAM_NAME_LEN);
+    (effective.to_string(), effective.len() < name.len())
+}
+
+/// Checks that the hub can store a program under `name` without cutting it off.
+/// Returns [`Error::FilenameTooLong`] if it's longer than [`MAX_PROGRAM_NAME_LEN`] bytes.
+pub fn validate_program_name(name: &str) -> Result<()> {
+    if name.len() > MAX_PROGRAM_NAME_LEN {
+        return Err(Error::FilenameTooLong(name.to_string()));
+    }
+    Ok(())
+}
+
+/// The longest name the hub will store, in bytes.
+pub const MAX_HUB_NAME_LEN: usize = 29;
+
+/// Checks that the hub can be given `name` without it being cut off.
+/// Returns [`Error::NameTooLong`] if it's longer than [`MAX_HUB_NAME_LEN`] bytes, or [`Error::EmptyName`] if it's empty.
+pub fn validate_hub_name(name: &str) -> Result<()> {
+    if name.is_empty() {
+        return Err(Error::EmptyName);
+    }
+    if name.len() > MAX_HUB_NAME_LEN {
+        return Err(Error::NameTooLong(name.len()));
+    }
+    Ok(())
+}
+
+/// Cuts off a string after `max_len` bytes, without splitting a character.
+fn truncate_str(str: &str, max_len: usize) -> &str {
+    let mut end = str.len().min(max_len);
+    while !str.is_char_boundary(end) {
+        end -= 1;
+    }
+    &str[..end]
+}
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub struct TransferChunkRequest<'a> {
     pub crc32: u32,
@@ -97,6 +253,7 @@ impl<'a> TransferChunkRequest<'a> {
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct BeginFirmwareUpdateRequest {
     pub file_sha: [u8; 20],
     pub crc32: u32,
@@ -121,13 +278,14 @@ impl<'a> SetHubNameRequest<'a> {
     pub fn serialize(self) -> Vec<u8> {
         let mut buf = Vec::new();
         buf.push(0x16); // ID
-        buf.extend_from_slice(&self.name.as_bytes()[..(29).min(self.name.len())]);
+        buf.extend_from_slice(truncate_str(self.name, MAX_HUB_NAME_LEN).as_bytes());
         buf.push(0x00);
         buf
     }
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ProgramFlowRequest {
     pub program_action: ProgramAction,
     pub program_slot: u8,
@@ -139,7 +297,47 @@ impl ProgramFlowRequest {
     }
 }
 
+/// The number of program slots on the hub.
+pub const PROGRAM_SLOTS: u8 = 20;
+
+/// A program slot on the hub, from 0 to 19.
+///
+/// Functions that take a slot accept either this or a [`u8`], which is checked before anything is sent to the hub.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ProgramSlot(u8);
+
+impl ProgramSlot {
+    /// Returns None if `slot` isn't a valid program slot.
+    pub fn new(slot: u8) -> Option<Self> {
+        (slot < PROGRAM_SLOTS).then_some(Self(slot))
+    }
+
+    pub fn get(self) -> u8 {
+        self.0
+    }
+
+    /// Returns every program slot, in order.
+    pub fn all() -> [ProgramSlot; PROGRAM_SLOTS as usize] {
+        std::array::from_fn(|slot| Self(slot as u8))
+    }
+}
+
+impl TryFrom<u8> for ProgramSlot {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        Self::new(value).ok_or(Error::InvalidSlot(value))
+    }
+}
+
+impl From<ProgramSlot> for u8 {
+    fn from(value: ProgramSlot) -> Self {
+        value.0
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ClearSlotRequest {
     pub program_slot: u8,
 }
@@ -166,6 +364,7 @@ impl<'a> TunnelMessage<'a> {
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct DeviceNotificationRequest {
     pub interval: u16,
 }
@@ -180,7 +379,10 @@ impl DeviceNotificationRequest {
 }
 
 /// Messages received from the SPIKE Prime
+///
+/// The messages all implement [`Default`], so they can easily be created by hand, like in tests: `InfoResponse { max_packet_size: 20, ..Default::default() }`.
 #[derive(Debug, PartialEq, Eq, Hash, Clone, FromVariants)]
+#[non_exhaustive]
 pub enum TxMessage {
     InfoResponse(InfoResponse),
     StartFirmwareUploadResponse(StartFirmwareUploadResponse),
@@ -196,12 +398,65 @@ pub enum TxMessage {
     ConsoleNotification(ConsoleNotification),
     DeviceNotificationResponse(DeviceNotificationResponse),
     DeviceNotification(DeviceNotification),
+    TunnelMessage(TunnelNotification),
+    /// A message this crate doesn't know about, like one added in newer firmware. Holds its ID and the bytes after it.
+    #[from_variants(skip)]
+    Unknown {
+        id: u8,
+        bytes: Vec<u8>,
+    },
+}
+
+/// Messages the hub sends in response to a request. See [`SpikeConnection::request`](crate::connection::SpikeConnection::request).
+pub trait ResponseMessage: Sized {
+    /// The name of the response's [`TxMessage`] variant, which [`Error::WrongMessage`] reports as expected.
+    const NAME: &'static str;
+
+    /// Returns the response if `message` is one, or gives the message back otherwise.
+    fn from_message(message: TxMessage) -> Result<Self, TxMessage>;
 }
 
+macro_rules! response_messages {
+    ($($variant:ident($response:ty)),* $(,)?) => {
+        $(
+            impl ResponseMessage for $response {
+                const NAME: &'static str = stringify!($variant);
+
+                fn from_message(message: TxMessage) -> Result<Self, TxMessage> {
+                    match message {
+                        TxMessage::$variant(r) => Ok(r),
+                        message => Err(message),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+response_messages!(
+    InfoResponse(InfoResponse),
+    StartFirmwareUploadResponse(StartFirmwareUploadResponse),
+    StartFileUploadResponse(StartFileUploadResponse),
+    TransferChunkResponse(TransferChunkResponse),
+    BeginFirmwareUpdateResponse(BeginFirmwareUpdateResponse),
+    SetHubNameResponse(SetHubNameResponse),
+    GetHubNameResponse(GetHubNameResponse),
+    DeviceUuidResponse(DeviceUuidResponse),
+    ProgramFlowResponse(ProgramFlowResponse),
+    ClearSlotResponse(ClearSlotResponse),
+    DeviceNotificationResponse(DeviceNotificationResponse),
+    TunnelMessage(TunnelNotification),
+);
+
 impl TxMessage {
     pub fn deserialize(data: Vec<u8>) -> Result<TxMessage> {
+        Self::deserialize_slice(&data)
+    }
+
+    /// Like [`TxMessage::deserialize`], but reads from a borrowed slice so the message doesn't need its own buffer.
+    pub fn deserialize_slice(data: &[u8]) -> Result<TxMessage> {
         let mut cursor = Cursor::new(data);
-        match cursor.read_u8()? {
+        match cursor.take_u8()? {
             0x01 => Ok(TxMessage::InfoResponse(InfoResponse::deserialize(cursor)?)),
             0x0b => Ok(TxMessage::StartFirmwareUploadResponse(
                 StartFirmwareUploadResponse::deserialize(cursor)?,
@@ -242,12 +497,40 @@ impl TxMessage {
             0x3c => Ok(TxMessage::DeviceNotification(
                 DeviceNotification::deserialize(cursor)?,
             )),
-            _ => Err(Error::UnknownMessage),
+            0x32 => Ok(TxMessage::TunnelMessage(TunnelNotification::deserialize(
+                cursor,
+            )?)),
+            id => Ok(TxMessage::Unknown {
+                id,
+                bytes: data[1..].to_vec(),
+            }),
+        }
+    }
+
+    /// Returns the name of the message's variant, like `"ConsoleNotification"`, for error messages.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TxMessage::InfoResponse(_) => "InfoResponse",
+            TxMessage::StartFirmwareUploadResponse(_) => "StartFirmwareUploadResponse",
+            TxMessage::StartFileUploadResponse(_) => "StartFileUploadResponse",
+            TxMessage::TransferChunkResponse(_) => "TransferChunkResponse",
+            TxMessage::BeginFirmwareUpdateResponse(_) => "BeginFirmwareUpdateResponse",
+            TxMessage::SetHubNameResponse(_) => "SetHubNameResponse",
+            TxMessage::GetHubNameResponse(_) => "GetHubNameResponse",
+            TxMessage::DeviceUuidResponse(_) => "DeviceUuidResponse",
+            TxMessage::ProgramFlowResponse(_) => "ProgramFlowResponse",
+            TxMessage::ProgramFlowNotification(_) => "ProgramFlowNotification",
+            TxMessage::ClearSlotResponse(_) => "ClearSlotResponse",
+            TxMessage::ConsoleNotification(_) => "ConsoleNotification",
+            TxMessage::DeviceNotificationResponse(_) => "DeviceNotificationResponse",
+            TxMessage::DeviceNotification(_) => "DeviceNotification",
+            TxMessage::TunnelMessage(_) => "TunnelMessage",
+            TxMessage::Unknown { .. } => "Unknown",
         }
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
 pub struct InfoResponse {
     pub rpc_major: u8,
     pub rpc_minor: u8,
@@ -258,21 +541,21 @@ pub struct InfoResponse {
     pub max_packet_size: u16,
     pub max_msg_size: u16,
     pub max_chunk_size: u16,
-    pub product_group_device_type: u16,
+    pub product_group_device_type: DeviceType,
 }
 
 impl InfoResponse {
-    pub fn deserialize(mut cursor: Cursor<Vec<u8>>) -> Result<Self> {
-        let rpc_major = cursor.read_u8()?;
-        let rpc_minor = cursor.read_u8()?;
-        let rpc_build = cursor.read_u16::<LittleEndian>()?;
-        let firmware_major = cursor.read_u8()?;
-        let firmware_minor = cursor.read_u8()?;
-        let firmware_build = cursor.read_u16::<LittleEndian>()?;
-        let max_packet_size = cursor.read_u16::<LittleEndian>()?;
-        let max_msg_size = cursor.read_u16::<LittleEndian>()?;
-        let max_chunk_size = cursor.read_u16::<LittleEndian>()?;
-        let product_group_device_type = cursor.read_u16::<LittleEndian>()?;
+    pub fn deserialize<T: AsRef<[u8]>>(mut cursor: Cursor<T>) -> Result<Self> {
+        let rpc_major = cursor.take_u8()?;
+        let rpc_minor = cursor.take_u8()?;
+        let rpc_build = cursor.take_u16()?;
+        let firmware_major = cursor.take_u8()?;
+        let firmware_minor = cursor.take_u8()?;
+        let firmware_build = cursor.take_u16()?;
+        let max_packet_size = cursor.take_u16()?;
+        let max_msg_size = cursor.take_u16()?;
+        let max_chunk_size = cursor.take_u16()?;
+        let product_group_device_type = DeviceType::from(cursor.take_u16()?);
 
         Ok(InfoResponse {
             rpc_major,
@@ -289,15 +572,15 @@ impl InfoResponse {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
 pub struct StartFirmwareUploadResponse {
     pub response_status: ResponseStatus,
     pub already_uploaded: u32,
 }
 
 impl StartFirmwareUploadResponse {
-    pub fn deserialize(mut cursor: Cursor<Vec<u8>>) -> Result<Self> {
-        let response_status = match cursor.read_u8()? {
+    pub fn deserialize<T: AsRef<[u8]>>(mut cursor: Cursor<T>) -> Result<Self> {
+        let response_status = match cursor.take_u8()? {
             0 => ResponseStatus::Acknowledged,
             1 => ResponseStatus::NotAcknowledged,
             i => {
@@ -307,7 +590,7 @@ impl StartFirmwareUploadResponse {
                 });
             }
         };
-        let already_uploaded = cursor.read_u32::<LittleEndian>()?;
+        let already_uploaded = cursor.take_u32()?;
         Ok(StartFirmwareUploadResponse {
             response_status,
             already_uploaded,
@@ -315,14 +598,14 @@ impl StartFirmwareUploadResponse {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
 pub struct StartFileUploadResponse {
     pub response_status: ResponseStatus,
 }
 
 impl StartFileUploadResponse {
-    pub fn deserialize(mut cursor: Cursor<Vec<u8>>) -> Result<Self> {
-        let response_status = match cursor.read_u8()? {
+    pub fn deserialize<T: AsRef<[u8]>>(mut cursor: Cursor<T>) -> Result<Self> {
+        let response_status = match cursor.take_u8()? {
             0 => ResponseStatus::Acknowledged,
             1 => ResponseStatus::NotAcknowledged,
             i => {
@@ -336,14 +619,14 @@ impl StartFileUploadResponse {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
 pub struct TransferChunkResponse {
     pub response_status: ResponseStatus,
 }
 
 impl TransferChunkResponse {
-    pub fn deserialize(mut cursor: Cursor<Vec<u8>>) -> Result<Self> {
-        let response_status = match cursor.read_u8()? {
+    pub fn deserialize<T: AsRef<[u8]>>(mut cursor: Cursor<T>) -> Result<Self> {
+        let response_status = match cursor.take_u8()? {
             0 => ResponseStatus::Acknowledged,
             1 => ResponseStatus::NotAcknowledged,
             i => {
@@ -357,14 +640,14 @@ impl TransferChunkResponse {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
 pub struct BeginFirmwareUpdateResponse {
     pub response_status: ResponseStatus,
 }
 
 impl BeginFirmwareUpdateResponse {
-    pub fn deserialize(mut cursor: Cursor<Vec<u8>>) -> Result<Self> {
-        let response_status = match cursor.read_u8()? {
+    pub fn deserialize<T: AsRef<[u8]>>(mut cursor: Cursor<T>) -> Result<Self> {
+        let response_status = match cursor.take_u8()? {
             0 => ResponseStatus::Acknowledged,
             1 => ResponseStatus::NotAcknowledged,
             i => {
@@ -378,14 +661,14 @@ impl BeginFirmwareUpdateResponse {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
 pub struct SetHubNameResponse {
     pub response_status: ResponseStatus,
 }
 
 impl SetHubNameResponse {
-    pub fn deserialize(mut cursor: Cursor<Vec<u8>>) -> Result<Self> {
-        let response_status = match cursor.read_u8()? {
+    pub fn deserialize<T: AsRef<[u8]>>(mut cursor: Cursor<T>) -> Result<Self> {
+        let response_status = match cursor.take_u8()? {
             0 => ResponseStatus::Acknowledged,
             1 => ResponseStatus::NotAcknowledged,
             i => {
@@ -399,40 +682,38 @@ impl SetHubNameResponse {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
 pub struct GetHubNameResponse {
     pub name: String,
 }
 
 impl GetHubNameResponse {
-    pub fn deserialize(mut cursor: Cursor<Vec<u8>>) -> Result<Self> {
-        let name = read_str(&mut cursor)?;
+    pub fn deserialize<T: AsRef<[u8]>>(mut cursor: Cursor<T>) -> Result<Self> {
+        let name = read_str(&mut cursor, MAX_NAME_READ_LEN)?;
         Ok(GetHubNameResponse { name })
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
 pub struct DeviceUuidResponse {
     pub uuid: Uuid,
 }
 
 impl DeviceUuidResponse {
-    pub fn deserialize(mut cursor: Cursor<Vec<u8>>) -> Result<Self> {
-        let mut buf = [0; 16];
-        cursor.read_exact(&mut buf)?;
-        let uuid = Uuid::from_bytes(buf);
+    pub fn deserialize<T: AsRef<[u8]>>(mut cursor: Cursor<T>) -> Result<Self> {
+        let uuid = Uuid::from_bytes(cursor.take_bytes()?);
         Ok(DeviceUuidResponse { uuid })
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
 pub struct ProgramFlowResponse {
     pub response_status: ResponseStatus,
 }
 
 impl ProgramFlowResponse {
-    pub fn deserialize(mut cursor: Cursor<Vec<u8>>) -> Result<Self> {
-        let response_status = match cursor.read_u8()? {
+    pub fn deserialize<T: AsRef<[u8]>>(mut cursor: Cursor<T>) -> Result<Self> {
+        let response_status = match cursor.take_u8()? {
             0 => ResponseStatus::Acknowledged,
             1 => ResponseStatus::NotAcknowledged,
             i => {
@@ -446,14 +727,17 @@ impl ProgramFlowResponse {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+/// Sent by the hub when a program starts or stops.
+///
+/// This doesn't say which slot the program was in, since the hub doesn't send it. The hub only runs one program at a time, so it's always the one that was started last.
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
 pub struct ProgramFlowNotification {
     pub program_action: ProgramAction,
 }
 
 impl ProgramFlowNotification {
-    pub fn deserialize(mut cursor: Cursor<Vec<u8>>) -> Result<Self> {
-        let program_action = match cursor.read_u8()? {
+    pub fn deserialize<T: AsRef<[u8]>>(mut cursor: Cursor<T>) -> Result<Self> {
+        let program_action = match cursor.take_u8()? {
             0 => ProgramAction::Start,
             1 => ProgramAction::Stop,
             i => {
@@ -467,14 +751,14 @@ impl ProgramFlowNotification {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
 pub struct ClearSlotResponse {
     pub response_status: ResponseStatus,
 }
 
 impl ClearSlotResponse {
-    pub fn deserialize(mut cursor: Cursor<Vec<u8>>) -> Result<Self> {
-        let response_status = match cursor.read_u8()? {
+    pub fn deserialize<T: AsRef<[u8]>>(mut cursor: Cursor<T>) -> Result<Self> {
+        let response_status = match cursor.take_u8()? {
             0 => ResponseStatus::Acknowledged,
             1 => ResponseStatus::NotAcknowledged,
             i => {
@@ -488,26 +772,81 @@ impl ClearSlotResponse {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
 pub struct ConsoleNotification {
     pub console_message: String,
 }
 
 impl ConsoleNotification {
-    pub fn deserialize(mut cursor: Cursor<Vec<u8>>) -> Result<Self> {
-        let console_message = read_str(&mut cursor)?;
+    pub fn deserialize<T: AsRef<[u8]>>(mut cursor: Cursor<T>) -> Result<Self> {
+        let console_message = read_str(&mut cursor, MAX_CONSOLE_READ_LEN)?;
         Ok(ConsoleNotification { console_message })
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+/// Collects [`ConsoleNotification`]s into whole lines, since the hub splits console output into notifications without caring where lines end.
+///
+/// Both `\n` and `\r\n` end a line, and are removed from the lines returned.
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
+pub struct ConsoleReader {
+    buffer: String,
+}
+
+impl ConsoleReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the output from a notification.
+    pub fn push(&mut self, notification: &ConsoleNotification) {
+        self.buffer.push_str(&notification.console_message);
+    }
+
+    /// Returns the next complete line, or None if the rest of the output doesn't end a line yet.
+    pub fn next_line(&mut self) -> Option<String> {
+        let end = self.buffer.find('\n')?;
+        let mut line: String = self.buffer.drain(..=end).collect();
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+        Some(line)
+    }
+
+    /// Returns the output that doesn't end a line yet, like when a program stopped partway through printing a line. Returns None if there isn't any.
+    /// Complete lines should be read with [`ConsoleReader::next_line`] first.
+    pub fn flush(&mut self) -> Option<String> {
+        let mut rest = std::mem::take(&mut self.buffer);
+        if rest.ends_with('\r') {
+            rest.pop();
+        }
+        (!rest.is_empty()).then_some(rest)
+    }
+}
+
+/// A tunnel message sent by the hub, usually from a program running on it.
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
+pub struct TunnelNotification {
+    pub payload: Vec<u8>,
+}
+
+impl TunnelNotification {
+    pub fn deserialize<T: AsRef<[u8]>>(mut cursor: Cursor<T>) -> Result<Self> {
+        let size = cursor.take_u16()?;
+        let mut payload = vec![0; size as usize];
+        cursor.read_exact(&mut payload).map_err(truncated)?;
+        Ok(TunnelNotification { payload })
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
 pub struct DeviceNotificationResponse {
     pub response_status: ResponseStatus,
 }
 
 impl DeviceNotificationResponse {
-    pub fn deserialize(mut cursor: Cursor<Vec<u8>>) -> Result<Self> {
-        let response_status = match cursor.read_u8()? {
+    pub fn deserialize<T: AsRef<[u8]>>(mut cursor: Cursor<T>) -> Result<Self> {
+        let response_status = match cursor.take_u8()? {
             0 => ResponseStatus::Acknowledged,
             1 => ResponseStatus::NotAcknowledged,
             i => {
@@ -521,24 +860,206 @@ impl DeviceNotificationResponse {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct DeviceNotification {
     pub payload: Vec<DeviceMessage>,
 }
 
 impl DeviceNotification {
-    pub fn deserialize(mut cursor: Cursor<Vec<u8>>) -> Result<Self> {
-        let size = cursor.read_u16::<LittleEndian>()?;
-        let start = cursor.position();
+    pub fn deserialize<T: AsRef<[u8]>>(mut cursor: Cursor<T>) -> Result<Self> {
+        let size = cursor.take_u16()?;
+        let mut bytes = vec![0; size as usize];
+        cursor.read_exact(&mut bytes).map_err(truncated)?;
+
+        let mut cursor = Cursor::new(bytes.as_slice());
         let mut payload = Vec::new();
-        while cursor.position() < start + size as u64 {
+        while (cursor.position() as usize) < bytes.len() {
             payload.push(DeviceMessage::deserialize(&mut cursor)?);
         }
         Ok(DeviceNotification { payload })
     }
+
+    /// Returns the device connected to each port. The battery, IMU, and matrix display aren't on a port, so they're left out.
+    pub fn port_map(&self) -> HashMap<HubPort, &DeviceMessage> {
+        self.payload
+            .iter()
+            .filter_map(|m| Some((m.port()?, m)))
+            .collect()
+    }
+
+    /// Returns an iterator over the messages in the notification, which is the same as `payload.iter()`.
+    pub fn iter(&self) -> std::slice::Iter<'_, DeviceMessage> {
+        self.payload.iter()
+    }
+
+    /// Returns the message for the device connected to `port`, if there is one.
+    pub fn device(&self, port: HubPort) -> Option<&DeviceMessage> {
+        self.payload.iter().find(|m| m.port() == Some(port))
+    }
+
+    /// Returns the hub's battery percentage.
+    pub fn battery(&self) -> Option<u8> {
+        self.payload.iter().find_map(|m| match m {
+            DeviceMessage::DeviceBattery(b) => Some(*b),
+            _ => None,
+        })
+    }
+
+    /// Returns the [`DeviceMessage::DeviceImuValues`] message.
+    pub fn imu(&self) -> Option<&DeviceMessage> {
+        self.payload
+            .iter()
+            .find(|m| matches!(m, DeviceMessage::DeviceImuValues { .. }))
+    }
+
+    /// Flattens the notification into a map of metric names to values, for logging to time-series databases.
+    ///
+    /// The keys are stable between versions, and are named after the fields of [`DeviceMessage`]. `{port}` is the port letter, like `C`:
+    /// - `battery`
+    /// - `imu.up_face`, `imu.yaw_face`: the [`HubFace`] discriminant
+    /// - `imu.yaw`, `imu.pitch`, `imu.roll`
+    /// - `imu.accelerometer_x`, `imu.accelerometer_y`, `imu.accelerometer_z`
+    /// - `imu.gyroscope_x`, `imu.gyroscope_y`, `imu.gyroscope_z`
+    /// - `display.0` through `display.24`: the brightness of each pixel, row by row
+    /// - `motor.{port}.device_type`: the [`MotorDeviceType`] discriminant
+    /// - `motor.{port}.absolute_position`, `motor.{port}.power`, `motor.{port}.speed`, `motor.{port}.position`
+    /// - `force.{port}.value`, `force.{port}.pressure`: 1 if pressed, 0 otherwise
+    /// - `color.{port}.color`: the [`Color`] discriminant, or -1 if no color was detected
+    /// - `color.{port}.red`, `color.{port}.green`, `color.{port}.blue`
+    /// - `distance.{port}.distance`
+    /// - `color_matrix.{port}.0` through `color_matrix.{port}.8`: each pixel, row by row
+    ///
+    /// [`DeviceMessage::Unknown`] devices are left out.
+    pub fn to_metrics(&self) -> HashMap<String, f64> {
+        let mut metrics = HashMap::new();
+        for message in &self.payload {
+            match message {
+                DeviceMessage::DeviceBattery(battery) => {
+                    metrics.insert("battery".to_string(), *battery as f64);
+                }
+                DeviceMessage::DeviceImuValues {
+                    up_face,
+                    yaw_face,
+                    yaw,
+                    pitch,
+                    roll,
+                    accelerometer_x,
+                    accelerometer_y,
+                    accelerometer_z,
+                    gyroscope_x,
+                    gyroscope_y,
+                    gyroscope_z,
+                } => {
+                    let values = [
+                        ("up_face", *up_face as u8 as f64),
+                        ("yaw_face", *yaw_face as u8 as f64),
+                        ("yaw", *yaw as f64),
+                        ("pitch", *pitch as f64),
+                        ("roll", *roll as f64),
+                        ("accelerometer_x", *accelerometer_x as f64),
+                        ("accelerometer_y", *accelerometer_y as f64),
+                        ("accelerometer_z", *accelerometer_z as f64),
+                        ("gyroscope_x", *gyroscope_x as f64),
+                        ("gyroscope_y", *gyroscope_y as f64),
+                        ("gyroscope_z", *gyroscope_z as f64),
+                    ];
+                    for (name, value) in values {
+                        metrics.insert(format!("imu.{name}"), value);
+                    }
+                }
+                DeviceMessage::Device5x5MatrixDisplay(pixels) => {
+                    for (i, pixel) in pixels.iter().enumerate() {
+                        metrics.insert(format!("display.{i}"), *pixel as f64);
+                    }
+                }
+                DeviceMessage::DeviceMotor {
+                    port,
+                    motor_device_type,
+                    absolute_position,
+                    power,
+                    speed,
+                    position,
+                } => {
+                    let values = [
+                        ("device_type", *motor_device_type as u8 as f64),
+                        ("absolute_position", *absolute_position as f64),
+                        ("power", *power as f64),
+                        ("speed", *speed as f64),
+                        ("position", *position as f64),
+                    ];
+                    for (name, value) in values {
+                        metrics.insert(format!("motor.{port:?}.{name}"), value);
+                    }
+                }
+                DeviceMessage::DeviceForceSensor {
+                    port,
+                    value,
+                    pressure,
+                } => {
+                    metrics.insert(format!("force.{port:?}.value"), *value as f64);
+                    metrics.insert(format!("force.{port:?}.pressure"), *pressure as u8 as f64);
+                }
+                DeviceMessage::DeviceColorSensor {
+                    port,
+                    color,
+                    red,
+                    green,
+                    blue,
+                } => {
+                    let color = color.map_or(-1.0, |c| c as u8 as f64);
+                    let values = [
+                        ("color", color),
+                        ("red", *red as f64),
+                        ("green", *green as f64),
+                        ("blue", *blue as f64),
+                    ];
+                    for (name, value) in values {
+                        metrics.insert(format!("color.{port:?}.{name}"), value);
+                    }
+                }
+                DeviceMessage::DeviceDistanceSensor { port, distance } => {
+                    metrics.insert(format!("distance.{port:?}.distance"), *distance as f64);
+                }
+                DeviceMessage::Device3x3ColorMatrix { port, pixels } => {
+                    for (i, pixel) in pixels.iter().enumerate() {
+                        metrics.insert(format!("color_matrix.{port:?}.{i}"), *pixel as f64);
+                    }
+                }
+                // Its values aren't known, so there's nothing stable to name them
+                DeviceMessage::Unknown { .. } => {}
+            }
+        }
+        metrics
+    }
+}
+
+impl IntoIterator for DeviceNotification {
+    type Item = DeviceMessage;
+    type IntoIter = std::vec::IntoIter<DeviceMessage>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.payload.into_iter()
+    }
 }
 
+impl<'a> IntoIterator for &'a DeviceNotification {
+    type Item = &'a DeviceMessage;
+    type IntoIter = std::slice::Iter<'a, DeviceMessage>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// With the `serde` feature, these are serialized with a `type` field naming the variant, and a `value` field holding its data.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(tag = "type", content = "value")
+)]
+#[non_exhaustive]
 pub enum DeviceMessage {
     /// The devices's battery in percentage
     DeviceBattery(u8),
@@ -572,55 +1093,60 @@ pub enum DeviceMessage {
     },
     DeviceColorSensor {
         port: HubPort,
+        /// The detected color, or None if the sensor didn't recognize one. The hub sends -1 when it doesn't, so any value that isn't a [`Color`] is treated that way instead of as an error.
         color: Option<Color>,
         red: u16,
         green: u16,
         blue: u16,
     },
+    /// A distance sensor. On the wire, this is the port followed by the distance as a little-endian `i16`.
+    /// The hub only reports a single distance for the sensor, not a reading for each of its zones.
     DeviceDistanceSensor {
         port: HubPort,
+        /// The distance to the nearest object in millimeters, or -1 if nothing was detected
         distance: i16,
     },
     Device3x3ColorMatrix {
         port: HubPort,
         pixels: [u8; 9],
     },
+    /// A device this crate doesn't know about. The messages don't say how long they are, so this holds the rest of the notification after its ID.
+    Unknown {
+        id: u8,
+        data: Vec<u8>,
+    },
 }
 
 impl DeviceMessage {
-    pub fn deserialize(cursor: &mut Cursor<Vec<u8>>) -> Result<Self> {
-        match cursor.read_u8()? {
-            0x00 => Ok(Self::DeviceBattery(cursor.read_u8()?)),
+    pub fn deserialize<T: AsRef<[u8]>>(cursor: &mut Cursor<T>) -> Result<Self> {
+        match cursor.take_u8()? {
+            0x00 => Ok(Self::DeviceBattery(cursor.take_u8()?)),
             0x01 => Ok(Self::DeviceImuValues {
-                up_face: cursor.read_u8()?.try_into()?,
-                yaw_face: cursor.read_u8()?.try_into()?,
-                yaw: cursor.read_i16::<LittleEndian>()?,
-                pitch: cursor.read_i16::<LittleEndian>()?,
-                roll: cursor.read_i16::<LittleEndian>()?,
-                accelerometer_x: cursor.read_i16::<LittleEndian>()?,
-                accelerometer_y: cursor.read_i16::<LittleEndian>()?,
-                accelerometer_z: cursor.read_i16::<LittleEndian>()?,
-                gyroscope_x: cursor.read_i16::<LittleEndian>()?,
-                gyroscope_y: cursor.read_i16::<LittleEndian>()?,
-                gyroscope_z: cursor.read_i16::<LittleEndian>()?,
+                up_face: cursor.take_u8()?.try_into()?,
+                yaw_face: cursor.take_u8()?.try_into()?,
+                yaw: cursor.take_i16()?,
+                pitch: cursor.take_i16()?,
+                roll: cursor.take_i16()?,
+                accelerometer_x: cursor.take_i16()?,
+                accelerometer_y: cursor.take_i16()?,
+                accelerometer_z: cursor.take_i16()?,
+                gyroscope_x: cursor.take_i16()?,
+                gyroscope_y: cursor.take_i16()?,
+                gyroscope_z: cursor.take_i16()?,
             }),
-            0x02 => Ok(Self::Device5x5MatrixDisplay({
-                let mut buf = [0; 25];
-                cursor.read_exact(&mut buf)?;
-                buf
-            })),
+            0x02 => Ok(Self::Device5x5MatrixDisplay(cursor.take_bytes::<25>()?)),
             0x0a => Ok(Self::DeviceMotor {
-                port: cursor.read_u8()?.try_into()?,
-                motor_device_type: cursor.read_u8()?.try_into()?,
-                absolute_position: cursor.read_i16::<LittleEndian>()?,
-                power: cursor.read_i16::<LittleEndian>()?,
-                speed: cursor.read_i8()?,
-                position: cursor.read_i32::<LittleEndian>()?,
+                port: cursor.take_u8()?.try_into()?,
+                motor_device_type: cursor.take_u8()?.try_into()?,
+                absolute_position: cursor.take_i16()?,
+                power: cursor.take_i16()?,
+                speed: cursor.take_i8()?,
+                position: cursor.take_i32()?,
             }),
             0x0b => Ok(Self::DeviceForceSensor {
-                port: cursor.read_u8()?.try_into()?,
-                value: cursor.read_u8()?,
-                pressure: match cursor.read_u8()? {
+                port: cursor.take_u8()?.try_into()?,
+                value: cursor.take_u8()?,
+                pressure: match cursor.take_u8()? {
                     0x01 => true,
                     0x00 => false,
                     i => {
@@ -632,55 +1158,469 @@ impl DeviceMessage {
                 },
             }),
             0x0c => Ok(Self::DeviceColorSensor {
-                port: cursor.read_u8()?.try_into()?,
-                color: cursor.read_u8()?.try_into().ok(),
-                red: cursor.read_u16::<LittleEndian>()?,
-                green: cursor.read_u16::<LittleEndian>()?,
-                blue: cursor.read_u16::<LittleEndian>()?,
+                port: cursor.take_u8()?.try_into()?,
+                color: cursor.take_u8()?.try_into().ok(),
+                red: cursor.take_u16()?,
+                green: cursor.take_u16()?,
+                blue: cursor.take_u16()?,
             }),
             0x0d => Ok(Self::DeviceDistanceSensor {
-                port: cursor.read_u8()?.try_into()?,
-                distance: cursor.read_i16::<LittleEndian>()?,
+                port: cursor.take_u8()?.try_into()?,
+                distance: cursor.take_i16()?,
             }),
             0x0e => Ok(Self::Device3x3ColorMatrix {
-                port: cursor.read_u8()?.try_into()?,
-                pixels: {
-                    let mut buf = [0; 9];
-                    cursor.read_exact(&mut buf)?;
-                    buf
-                },
+                port: cursor.take_u8()?.try_into()?,
+                pixels: cursor.take_bytes::<9>()?,
+            }),
+            id => {
+                // There's no way to tell where an unknown device's message ends, so it gets the rest
+                let mut data = Vec::new();
+                cursor.read_to_end(&mut data).map_err(truncated)?;
+                Ok(Self::Unknown { id, data })
+            }
+        }
+    }
+
+    /// Returns the port the device is connected to, or None if it's part of the hub or unknown.
+    pub fn port(&self) -> Option<HubPort> {
+        match self {
+            Self::DeviceMotor { port, .. }
+            | Self::DeviceForceSensor { port, .. }
+            | Self::DeviceColorSensor { port, .. }
+            | Self::DeviceDistanceSensor { port, .. }
+            | Self::Device3x3ColorMatrix { port, .. } => Some(*port),
+            Self::DeviceBattery(_)
+            | Self::DeviceImuValues { .. }
+            | Self::Device5x5MatrixDisplay(_)
+            | Self::Unknown { .. } => None,
+        }
+    }
+
+    /// Returns the distance sensor's port and distance, if this is a [`DeviceMessage::DeviceDistanceSensor`].
+    pub fn as_distance(&self) -> Option<(HubPort, i16)> {
+        match self {
+            Self::DeviceDistanceSensor { port, distance } => Some((*port, *distance)),
+            _ => None,
+        }
+    }
+
+    /// Returns the color sensor's reading, if this is a [`DeviceMessage::DeviceColorSensor`].
+    pub fn as_color(&self) -> Option<ColorReading> {
+        match self {
+            Self::DeviceColorSensor {
+                port,
+                color,
+                red,
+                green,
+                blue,
+            } => Some(ColorReading {
+                port: *port,
+                color: *color,
+                red: *red,
+                green: *green,
+                blue: *blue,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns the hub's motion sensor reading, if this is a [`DeviceMessage::DeviceImuValues`].
+    pub fn as_imu(&self) -> Option<ImuReading> {
+        match self {
+            Self::DeviceImuValues {
+                up_face,
+                yaw_face,
+                yaw,
+                pitch,
+                roll,
+                accelerometer_x,
+                accelerometer_y,
+                accelerometer_z,
+                gyroscope_x,
+                gyroscope_y,
+                gyroscope_z,
+            } => Some(ImuReading {
+                up_face: *up_face,
+                yaw_face: *yaw_face,
+                yaw: *yaw,
+                pitch: *pitch,
+                roll: *roll,
+                accelerometer: [*accelerometer_x, *accelerometer_y, *accelerometer_z],
+                gyroscope: [*gyroscope_x, *gyroscope_y, *gyroscope_z],
             }),
-            _ => Err(Error::UnknownMessage),
+            _ => None,
+        }
+    }
+
+    /// Returns the motor's reading, if this is a [`DeviceMessage::DeviceMotor`].
+    pub fn as_motor(&self) -> Option<MotorReading> {
+        match self {
+            Self::DeviceMotor {
+                port,
+                motor_device_type,
+                absolute_position,
+                power,
+                speed,
+                position,
+            } => Some(MotorReading {
+                port: *port,
+                motor_device_type: *motor_device_type,
+                absolute_position: *absolute_position,
+                power: *power,
+                speed: *speed,
+                position: *position,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns the force sensor's reading, if this is a [`DeviceMessage::DeviceForceSensor`].
+    pub fn as_force(&self) -> Option<ForceReading> {
+        match self {
+            Self::DeviceForceSensor {
+                port,
+                value,
+                pressure,
+            } => Some(ForceReading {
+                port: *port,
+                value: *value,
+                pressure: *pressure,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns the pixels of the matrix display, if this is a [`DeviceMessage::Device5x5MatrixDisplay`].
+    pub fn as_matrix_display(&self) -> Option<MatrixDisplay> {
+        match self {
+            Self::Device5x5MatrixDisplay(pixels) => Some(MatrixDisplay(*pixels)),
+            _ => None,
+        }
+    }
+
+    /// Returns the pixels of a 3x3 color light matrix as rows, from top to bottom, if this is a [`DeviceMessage::Device3x3ColorMatrix`].
+    pub fn as_color_matrix(&self) -> Option<[[ColorMatrixPixel; 3]; 3]> {
+        match self {
+            Self::Device3x3ColorMatrix { pixels, .. } => Some(std::array::from_fn(|row| {
+                std::array::from_fn(|column| ColorMatrixPixel::from(pixels[row * 3 + column]))
+            })),
+            _ => None,
+        }
+    }
+}
+
+/// A reading from a color sensor. See [`DeviceMessage::as_color`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ColorReading {
+    pub port: HubPort,
+    pub color: Option<Color>,
+    pub red: u16,
+    pub green: u16,
+    pub blue: u16,
+}
+
+/// A reading from a motor. See [`DeviceMessage::as_motor`].
+///
+/// | Field               | Unit                                  | Range       |
+/// |---------------------|---------------------------------------|-------------|
+/// | `absolute_position` | degrees from the zero mark            | -180 to 179 |
+/// | `power`             | percent of full power                 | -100 to 100 |
+/// | `speed`             | percent of full speed                 | -100 to 100 |
+/// | `position`          | degrees since the motor was connected | any         |
+///
+/// Negative power and speed mean the motor is turning counterclockwise.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MotorReading {
+    pub port: HubPort,
+    pub motor_device_type: MotorDeviceType,
+    pub absolute_position: i16,
+    pub power: i16,
+    pub speed: i8,
+    pub position: i32,
+}
+
+impl MotorReading {
+    /// Returns how far the motor has turned since it was connected, in degrees. This keeps counting past a full rotation.
+    pub fn degrees(&self) -> i32 {
+        self.position
+    }
+
+    /// Returns the motor's position relative to the zero mark on it, in degrees from -180 to 179.
+    pub fn absolute_degrees(&self) -> i16 {
+        self.absolute_position
+    }
+
+    /// Returns the power given to the motor, as a percent from -100 to 100.
+    pub fn power_percent(&self) -> i16 {
+        self.power
+    }
+
+    /// Returns the motor's speed, as a percent of its full speed from -100 to 100.
+    pub fn speed_percent(&self) -> i8 {
+        self.speed
+    }
+}
+
+/// A reading from the hub's motion sensor. See [`DeviceMessage::as_imu`].
+///
+/// The axes are fixed to the hub: x points out of the [`HubFace::Front`], y out of the [`HubFace::Right`], and z out of the [`HubFace::Top`], which has the display on it.
+///
+/// | Field                  | Unit                          |
+/// |------------------------|-------------------------------|
+/// | `yaw`, `pitch`, `roll` | tenths of a degree            |
+/// | `accelerometer`        | thousandths of a g (milli-g)  |
+/// | `gyroscope`            | tenths of a degree per second |
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ImuReading {
+    /// The face of the hub pointing up.
+    pub up_face: HubFace,
+    /// The face of the hub that yaw is measured around, which is the face that was up when the yaw was last reset.
+    pub yaw_face: HubFace,
+    pub yaw: i16,
+    pub pitch: i16,
+    pub roll: i16,
+    /// The acceleration along the x, y, and z axes.
+    pub accelerometer: [i16; 3],
+    /// The rotation speed around the x, y, and z axes.
+    pub gyroscope: [i16; 3],
+}
+
+impl ImuReading {
+    /// Returns the yaw, which is how far the hub has turned around the axis of [`ImuReading::yaw_face`], in degrees from -180 to 180.
+    pub fn yaw_degrees(&self) -> f32 {
+        self.yaw as f32 / 10.0
+    }
+
+    /// Returns the pitch, which is how far the front of the hub is tilted up, in degrees from -90 to 90.
+    pub fn pitch_degrees(&self) -> f32 {
+        self.pitch as f32 / 10.0
+    }
+
+    /// Returns the roll, which is how far the hub is tilted to the side, in degrees from -180 to 180.
+    pub fn roll_degrees(&self) -> f32 {
+        self.roll as f32 / 10.0
+    }
+
+    /// Returns the acceleration along the x, y, and z axes, in g. A hub lying still has about 1 g pointing out of its up face, from gravity.
+    pub fn acceleration_g(&self) -> [f32; 3] {
+        self.accelerometer.map(|a| a as f32 / 1000.0)
+    }
+
+    /// Returns the rotation speed around the x, y, and z axes, in degrees per second.
+    pub fn gyro_dps(&self) -> [f32; 3] {
+        self.gyroscope.map(|g| g as f32 / 10.0)
+    }
+
+    /// Returns the hub's orientation as a unit quaternion `[w, x, y, z]`, made by rotating by the yaw around z, then the pitch around y, then the roll around x.
+    pub fn to_quaternion(&self) -> [f32; 4] {
+        let (sy, cy) = (self.yaw_degrees().to_radians() / 2.0).sin_cos();
+        let (sp, cp) = (self.pitch_degrees().to_radians() / 2.0).sin_cos();
+        let (sr, cr) = (self.roll_degrees().to_radians() / 2.0).sin_cos();
+        [
+            cr * cp * cy + sr * sp * sy,
+            sr * cp * cy - cr * sp * sy,
+            cr * sp * cy + sr * cp * sy,
+            cr * cp * sy - sr * sp * cy,
+        ]
+    }
+}
+
+/// A reading from a force sensor. See [`DeviceMessage::as_force`].
+///
+/// `value` is the force from 0 to 255, which covers the sensor's range of 0 to 10 newtons, so each step is about 0.04 N.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ForceReading {
+    pub port: HubPort,
+    pub value: u8,
+    /// Whether the sensor's button is pressed in.
+    pub pressure: bool,
+}
+
+impl ForceReading {
+    /// The force a `value` of 255 stands for, in newtons.
+    pub const MAX_NEWTONS: f32 = 10.0;
+
+    /// Returns the force in newtons, from 0 to 10. This is approximate, since the sensor isn't calibrated.
+    pub fn newtons(&self) -> f32 {
+        self.value as f32 * Self::MAX_NEWTONS / u8::MAX as f32
+    }
+
+    /// Returns whether the sensor's button is pressed in, which can happen before any force is measured.
+    pub fn is_pressed(&self) -> bool {
+        self.pressure
+    }
+}
+
+/// A pixel of a 3x3 color light matrix. See [`DeviceMessage::as_color_matrix`].
+///
+/// Each pixel is sent as a byte, with the color in the low 4 bits and the brightness in the high 4 bits: `brightness << 4 | color`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ColorMatrixPixel {
+    /// The color of the pixel, or None if it isn't one of the [`Color`]s.
+    pub color: Option<Color>,
+    /// The brightness of the pixel, from 0 (off) to 10.
+    pub brightness: u8,
+}
+
+impl From<u8> for ColorMatrixPixel {
+    fn from(value: u8) -> Self {
+        Self {
+            color: Color::try_from(value & 0x0f).ok(),
+            brightness: value >> 4,
+        }
+    }
+}
+
+/// The brightness of each pixel on the hub's 5x5 matrix display, from 0 to 100, row by row.
+///
+/// This implements [`Display`](fmt::Display) to draw the display as ASCII art, which is handy for debugging.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MatrixDisplay(pub [u8; 25]);
+
+impl MatrixDisplay {
+    /// Returns the pixels as rows, from top to bottom.
+    pub fn as_grid(&self) -> [[u8; 5]; 5] {
+        std::array::from_fn(|row| std::array::from_fn(|column| self.0[row * 5 + column]))
+    }
+}
+
+impl fmt::Display for MatrixDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, row) in self.as_grid().iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            for brightness in row {
+                let pixel = match brightness {
+                    0 => ' ',
+                    1..=33 => '.',
+                    34..=66 => 'o',
+                    _ => '#',
+                };
+                write!(f, "{pixel}")?;
+            }
         }
+        Ok(())
     }
 }
 
-fn read_str(cursor: &mut Cursor<Vec<u8>>) -> Result<String> {
+/// Reads values from a message, returning [`Error::TruncatedMessage`] instead of an I/O error if the message ends before the value.
+trait MessageReader: Read {
+    fn take_u8(&mut self) -> Result<u8> {
+        self.read_u8().map_err(truncated)
+    }
+
+    fn take_i8(&mut self) -> Result<i8> {
+        self.read_i8().map_err(truncated)
+    }
+
+    fn take_u16(&mut self) -> Result<u16> {
+        self.read_u16::<LittleEndian>().map_err(truncated)
+    }
+
+    fn take_i16(&mut self) -> Result<i16> {
+        self.read_i16::<LittleEndian>().map_err(truncated)
+    }
+
+    fn take_u32(&mut self) -> Result<u32> {
+        self.read_u32::<LittleEndian>().map_err(truncated)
+    }
+
+    fn take_i32(&mut self) -> Result<i32> {
+        self.read_i32::<LittleEndian>().map_err(truncated)
+    }
+
+    fn take_bytes<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut buf = [0; N];
+        self.read_exact(&mut buf).map_err(truncated)?;
+        Ok(buf)
+    }
+}
+
+impl<R: Read> MessageReader for R {}
+
+fn truncated(e: io::Error) -> Error {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        Error::TruncatedMessage
+    } else {
+        Error::Io(e)
+    }
+}
+
+/// The longest name [`read_str`] reads, which is plenty for the names the hub can store.
+const MAX_NAME_READ_LEN: usize = 64;
+/// The longest console message [`read_str`] reads.
+const MAX_CONSOLE_READ_LEN: usize = 4096;
+
+/// Reads a null terminated string. Returns [`Error::MalformedFrame`] if there's no terminator within `max_len` bytes, so garbage can't make an absurdly long string.
+fn read_str<T: AsRef<[u8]>>(cursor: &mut Cursor<T>, max_len: usize) -> Result<String> {
     let mut str = Vec::new();
     loop {
-        let byte = cursor.read_u8()?;
+        let byte = cursor.take_u8()?;
         if byte == 0 {
             break;
         }
+        if str.len() == max_len {
+            return Err(Error::MalformedFrame);
+        }
         str.push(byte);
     }
-    let str = String::from_utf8(str).expect("string should be valid UTF-8");
-    Ok(str)
+    Ok(String::from_utf8(str)?)
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
 pub enum ProgramAction {
+    #[default]
     Start = 0x00,
     Stop = 0x01,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
 pub enum ResponseStatus {
+    #[default]
     Acknowledged = 0x00,
     NotAcknowledged = 0x01,
 }
 
+/// The kind of hub, from its [`InfoResponse`]. These all speak the same protocol, but only SPIKE Primes are connected to by default.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum DeviceType {
+    #[default]
+    SpikePrime,
+    SpikeEssential,
+    RobotInventor,
+    Unknown(u16),
+}
+
+impl From<u16> for DeviceType {
+    fn from(value: u16) -> Self {
+        match value {
+            0x00 => DeviceType::SpikePrime,
+            0x01 => DeviceType::SpikeEssential,
+            0x02 => DeviceType::RobotInventor,
+            value => DeviceType::Unknown(value),
+        }
+    }
+}
+
+impl From<DeviceType> for u16 {
+    fn from(value: DeviceType) -> Self {
+        match value {
+            DeviceType::SpikePrime => 0x00,
+            DeviceType::SpikeEssential => 0x01,
+            DeviceType::RobotInventor => 0x02,
+            DeviceType::Unknown(value) => value,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
 pub enum HubFace {
     Top = 0x00,
     Front = 0x01,
@@ -709,7 +1649,28 @@ impl TryFrom<u8> for HubFace {
     }
 }
 
+impl HubFace {
+    /// Returns every face, in order.
+    pub fn all() -> [HubFace; 6] {
+        [
+            HubFace::Top,
+            HubFace::Front,
+            HubFace::Right,
+            HubFace::Bottom,
+            HubFace::Back,
+            HubFace::Left,
+        ]
+    }
+
+    /// Returns the number the protocol uses for the face.
+    pub fn index(self) -> u8 {
+        self as u8
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
 pub enum HubPort {
     A = 0x00,
     B = 0x01,
@@ -738,7 +1699,30 @@ impl TryFrom<u8> for HubPort {
     }
 }
 
+impl HubPort {
+    /// Returns every port, in order.
+    pub fn all() -> [HubPort; 6] {
+        [
+            HubPort::A,
+            HubPort::B,
+            HubPort::C,
+            HubPort::D,
+            HubPort::E,
+            HubPort::F,
+        ]
+    }
+
+    /// Returns the number the protocol uses for the port, from 0 for A to 5 for F.
+    pub fn index(self) -> u8 {
+        self as u8
+    }
+}
+
+/// The direction a motor turns to reach a position, matching the constants in the hub's `motor` python module.
+///
+/// The protocol has no message for controlling motors, so this isn't sent to the hub directly. It's meant for generating programs that move motors, which can then be uploaded and started.
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
 pub enum MotorMoveDirection {
     Clockwise = 0x00,
     CounterClockwise = 0x01,
@@ -746,7 +1730,21 @@ pub enum MotorMoveDirection {
     LongestPath = 0x03,
 }
 
+impl MotorMoveDirection {
+    /// Returns the name of the matching constant in the hub's python API, like `motor.SHORTEST_PATH`.
+    pub fn python_constant(self) -> &'static str {
+        match self {
+            MotorMoveDirection::Clockwise => "motor.CLOCKWISE",
+            MotorMoveDirection::CounterClockwise => "motor.COUNTERCLOCKWISE",
+            MotorMoveDirection::ShortestPath => "motor.SHORTEST_PATH",
+            MotorMoveDirection::LongestPath => "motor.LONGEST_PATH",
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
 pub enum MotorDeviceType {
     Medium = 0x30,
     Large = 0x31,
@@ -769,7 +1767,12 @@ impl TryFrom<u8> for MotorDeviceType {
     }
 }
 
+/// A color the hub's sensors and lights use.
+///
+/// There's no catch-all for colors newer firmware might add, since readings of unknown colors are already None, like [`DeviceMessage::DeviceColorSensor`]'s `color`.
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
 pub enum Color {
     Black = 0x00,
     Magenta = 0x01,
@@ -785,9 +1788,9 @@ pub enum Color {
 }
 
 impl TryFrom<u8> for Color {
-    type Error = ();
+    type Error = Error;
 
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
+    fn try_from(value: u8) -> Result<Self> {
         match value {
             0x00 => Ok(Self::Black),
             0x01 => Ok(Self::Magenta),
@@ -800,7 +1803,73 @@ impl TryFrom<u8> for Color {
             0x08 => Ok(Self::Orange),
             0x09 => Ok(Self::Red),
             0x0a => Ok(Self::White),
-            _ => Err(()),
+            _ => Err(Error::InvalidEnumValue {
+                enum_name: "Color",
+                value,
+            }),
+        }
+    }
+}
+
+impl Color {
+    const ALL: [Color; 11] = [
+        Self::Black,
+        Self::Magenta,
+        Self::Purple,
+        Self::Blue,
+        Self::Azure,
+        Self::Turquoise,
+        Self::Green,
+        Self::Yellow,
+        Self::Orange,
+        Self::Red,
+        Self::White,
+    ];
+
+    /// Returns an approximate RGB value for the color, based on the LEGO brick it's named after.
+    pub fn to_rgb(self) -> (u8, u8, u8) {
+        match self {
+            Self::Black => (27, 42, 52),
+            Self::Magenta => (146, 57, 120),
+            Self::Purple => (129, 0, 123),
+            Self::Blue => (0, 85, 191),
+            Self::Azure => (54, 174, 191),
+            Self::Turquoise => (0, 143, 155),
+            Self::Green => (75, 151, 74),
+            Self::Yellow => (242, 205, 55),
+            Self::Orange => (254, 138, 24),
+            Self::Red => (201, 26, 9),
+            Self::White => (244, 244, 244),
         }
     }
+
+    /// Returns the lowercase name of the color, like `"azure"`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Black => "black",
+            Self::Magenta => "magenta",
+            Self::Purple => "purple",
+            Self::Blue => "blue",
+            Self::Azure => "azure",
+            Self::Turquoise => "turquoise",
+            Self::Green => "green",
+            Self::Yellow => "yellow",
+            Self::Orange => "orange",
+            Self::Red => "red",
+            Self::White => "white",
+        }
+    }
+
+    /// Parses a color from its name, ignoring case. See [`Color::name`].
+    pub fn from_name(s: &str) -> Option<Color> {
+        Self::ALL
+            .into_iter()
+            .find(|c| c.name().eq_ignore_ascii_case(s.trim()))
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
 }
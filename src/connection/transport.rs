@@ -0,0 +1,445 @@
+//! Module for the ways bytes can get to and from a hub. See [`Transport`].
+
+use std::{
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use btleplug::{
+    api::{Characteristic, Peripheral as _, WriteType},
+    platform::{Peripheral, PeripheralId},
+};
+use futures::{FutureExt, Stream, StreamExt, future::BoxFuture};
+use uuid::Uuid;
+
+use crate::error::*;
+
+/// The bytes received from a hub. Each item can hold part of a frame, a whole one, or several.
+pub type ByteStream = Pin<Box<dyn Stream<Item = Vec<u8>> + Send>>;
+
+/// Carries the framed bytes of the SPIKE Prime protocol between a [`SpikeConnection`](super::SpikeConnection) and a hub.
+///
+/// The hub speaks the same protocol over Bluetooth ([`BleTransport`]) and its USB serial port (`SerialTransport`, with the `serial` feature).
+/// Implement this to talk to a hub some other way, and connect with [`SpikeConnection::from_transport`](super::SpikeConnection::from_transport).
+pub trait Transport: Send + Sync {
+    /// Connects to the hub, returning the bytes it sends. This is called again by [`SpikeConnection::reconnect`](super::SpikeConnection::reconnect), and the old stream is dropped.
+    fn connect(&mut self) -> BoxFuture<'_, Result<ByteStream>>;
+
+    /// Writes bytes to the hub. Returns [`Error::Disconnected`] if the hub isn't connected.
+    ///
+    /// `write_type` is only a hint, for transports like Bluetooth that can write without waiting for a response.
+    fn write<'a>(&'a self, bytes: &'a [u8], write_type: WriteType) -> BoxFuture<'a, Result<()>>;
+
+    /// Connects to the hub again after a write failed, returning the bytes it sends from then on. The stream returned before is dropped.
+    /// Used by [`SpikeConnection::set_auto_reconnect`](super::SpikeConnection::set_auto_reconnect). Transports that can't do this return [`Error::Disconnected`], which is the default.
+    fn relink(&self) -> BoxFuture<'_, Result<ByteStream>> {
+        async { Err(Error::Disconnected) }.boxed()
+    }
+
+    /// Disconnects from the hub. Does nothing if it isn't connected, which is the default.
+    fn disconnect(&mut self) -> BoxFuture<'_, Result<()>> {
+        async { Ok(()) }.boxed()
+    }
+
+    /// Returns the Bluetooth id of the hub, if it's connected over Bluetooth.
+    fn peripheral_id(&self) -> Option<PeripheralId> {
+        None
+    }
+}
+
+/// Stands in for the transport of a connection that was disconnected.
+pub(crate) struct Closed;
+
+impl Transport for Closed {
+    fn connect(&mut self) -> BoxFuture<'_, Result<ByteStream>> {
+        async { Err(Error::Disconnected) }.boxed()
+    }
+
+    fn write<'a>(&'a self, _bytes: &'a [u8], _write_type: WriteType) -> BoxFuture<'a, Result<()>> {
+        async { Err(Error::Disconnected) }.boxed()
+    }
+}
+
+/// Talks to a hub over Bluetooth Low Energy. This is what [`SpikePrime::connect`](crate::SpikePrime::connect) uses.
+pub struct BleTransport {
+    peripheral: Peripheral,
+    /// RX (from the hub's perspective)
+    rx: Option<Characteristic>,
+    /// TX (from the hub's perspective)
+    tx: Option<Characteristic>,
+    /// Whether notifications from `tx` are subscribed to, so they're never subscribed to twice.
+    /// Atomic since relinking only has `&self`.
+    subscribed: AtomicBool,
+}
+
+impl BleTransport {
+    pub fn new(peripheral: Peripheral) -> Self {
+        Self {
+            peripheral,
+            rx: None,
+            tx: None,
+            subscribed: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the Bluetooth peripheral of the hub.
+    pub fn peripheral(&self) -> &Peripheral {
+        &self.peripheral
+    }
+
+    async fn subscribe(&self, tx: &Characteristic) -> Result<()> {
+        self.unsubscribe().await;
+        self.peripheral.subscribe(tx).await?;
+        self.subscribed.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn unsubscribe(&self) {
+        if let Some(tx) = &self.tx
+            && self.subscribed.swap(false, Ordering::Relaxed)
+        {
+            // The subscription is already gone if the hub disconnected
+            let _ = self.peripheral.unsubscribe(tx).await;
+        }
+    }
+}
+
+impl Transport for BleTransport {
+    fn connect(&mut self) -> BoxFuture<'_, Result<ByteStream>> {
+        async {
+            const RX_UUID: Uuid = Uuid::from_bytes([
+                0x00, 0x00, 0xFD, 0x02, 0x00, 0x01, 0x10, 0x00, 0x80, 0x00, 0x00, 0x80, 0x5F, 0x9B,
+                0x34, 0xFB,
+            ]);
+            const TX_UUID: Uuid = Uuid::from_bytes([
+                0x00, 0x00, 0xFD, 0x02, 0x00, 0x02, 0x10, 0x00, 0x80, 0x00, 0x00, 0x80, 0x5F, 0x9B,
+                0x34, 0xFB,
+            ]);
+
+            self.peripheral.connect().await?;
+            self.peripheral.discover_services().await?;
+            let mut rx = None;
+            let mut tx = None;
+            for i in self.peripheral.characteristics() {
+                match i.uuid {
+                    RX_UUID => {
+                        rx = Some(i);
+                    }
+                    TX_UUID => {
+                        tx = Some(i);
+                    }
+                    _ => {}
+                }
+            }
+
+            let tx = tx.ok_or(Error::BadDevice)?;
+            let rx = rx.ok_or(Error::BadDevice)?;
+
+            self.subscribe(&tx).await?;
+            let notifications = self.peripheral.notifications().await?;
+
+            self.rx = Some(rx);
+            self.tx = Some(tx);
+            Ok(notifications.map(|n| n.value).boxed())
+        }
+        .boxed()
+    }
+
+    fn write<'a>(&'a self, bytes: &'a [u8], write_type: WriteType) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let rx = self.rx.as_ref().ok_or(Error::Disconnected)?;
+            self.peripheral.write(rx, bytes, write_type).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn relink(&self) -> BoxFuture<'_, Result<ByteStream>> {
+        async {
+            let tx = self.tx.as_ref().ok_or(Error::Disconnected)?;
+            self.peripheral.connect().await?;
+            self.peripheral.discover_services().await?;
+            self.subscribe(tx).await?;
+            let notifications = self.peripheral.notifications().await?;
+            Ok(notifications.map(|n| n.value).boxed())
+        }
+        .boxed()
+    }
+
+    fn disconnect(&mut self) -> BoxFuture<'_, Result<()>> {
+        async {
+            self.unsubscribe().await;
+            self.peripheral.disconnect().await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn peripheral_id(&self) -> Option<PeripheralId> {
+        Some(self.peripheral.id())
+    }
+}
+
+#[cfg(feature = "serial")]
+pub use serial::SerialTransport;
+
+#[cfg(feature = "serial")]
+mod serial {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, WriteHalf},
+        sync::Mutex,
+    };
+    use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+    use super::*;
+
+    const BAUD_RATE: u32 = 115200;
+    const READ_BUFFER_SIZE: usize = 1024;
+
+    /// Talks to a hub over its USB serial port, which is faster and more reliable than Bluetooth. Requires the `serial` feature.
+    pub struct SerialTransport {
+        path: String,
+        writer: Mutex<Option<WriteHalf<SerialStream>>>,
+    }
+
+    impl SerialTransport {
+        /// Creates a transport for the serial port at `path`, like `/dev/ttyACM0` or `COM3`. The port isn't opened until it's connected.
+        pub fn new(path: impl Into<String>) -> Self {
+            Self {
+                path: path.into(),
+                writer: Mutex::new(None),
+            }
+        }
+    }
+
+    impl Transport for SerialTransport {
+        fn connect(&mut self) -> BoxFuture<'_, Result<ByteStream>> {
+            async {
+                let port = tokio_serial::new(&self.path, BAUD_RATE)
+                    .open_native_async()
+                    .map_err(std::io::Error::from)?;
+                let (reader, writer) = tokio::io::split(port);
+                *self.writer.get_mut() = Some(writer);
+
+                let bytes = futures::stream::unfold(reader, async |mut reader| {
+                    let mut buf = vec![0; READ_BUFFER_SIZE];
+                    match reader.read(&mut buf).await {
+                        Ok(0) | Err(_) => None,
+                        Ok(len) => {
+                            buf.truncate(len);
+                            Some((buf, reader))
+                        }
+                    }
+                });
+                Ok(bytes.boxed())
+            }
+            .boxed()
+        }
+
+        fn write<'a>(
+            &'a self,
+            bytes: &'a [u8],
+            _write_type: WriteType,
+        ) -> BoxFuture<'a, Result<()>> {
+            async move {
+                let mut writer = self.writer.lock().await;
+                let writer = writer.as_mut().ok_or(Error::Disconnected)?;
+                writer.write_all(bytes).await?;
+                writer.flush().await?;
+                Ok(())
+            }
+            .boxed()
+        }
+
+        fn disconnect(&mut self) -> BoxFuture<'_, Result<()>> {
+            async {
+                if let Some(mut writer) = self.writer.get_mut().take() {
+                    writer.shutdown().await?;
+                }
+                Ok(())
+            }
+            .boxed()
+        }
+    }
+}
+
+#[cfg(feature = "test-util")]
+pub use mock::MockTransport;
+
+#[cfg(feature = "test-util")]
+mod mock {
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex, PoisonError},
+    };
+
+    use tokio::sync::mpsc::{self, UnboundedSender};
+
+    use super::*;
+    use crate::connection::{cobs, message::RxMessage};
+
+    /// The [`InfoResponse`](crate::connection::message::InfoResponse) of a SPIKE Prime, which [`MockTransport`] answers the first [`RxMessage::InfoRequest`] with.
+    const INFO_RESPONSE: [u8; 17] = [
+        0x01, // ID
+        0x01, 0x00, 0x00, 0x00, // RPC version
+        0x01, 0x00, 0x00, 0x00, // Firmware version
+        0x14, 0x00, // Max packet size
+        0x10, 0x02, // Max message size
+        0x00, 0x02, // Max chunk size
+        0x00, 0x00, // SPIKE Prime
+    ];
+
+    /// A fake hub for testing, which answers requests from a script instead of talking to a real hub. Requires the `test-util` feature.
+    ///
+    /// Each request written to it has to be the next one expected with [`MockTransport::expect`], or writing it panics.
+    /// Clones share the same script, so keep a clone around to check what was written after passing one to [`SpikeConnection::from_transport`](super::super::SpikeConnection::from_transport).
+    #[derive(Clone)]
+    pub struct MockTransport {
+        state: Arc<Mutex<MockState>>,
+    }
+
+    #[derive(Default)]
+    struct MockState {
+        /// Each expected message, and the messages to respond to it with.
+        script: VecDeque<(Vec<u8>, Vec<Vec<u8>>)>,
+        /// The frames written so far, encoded.
+        written: Vec<Vec<u8>>,
+        /// Bytes of a frame that hasn't been completely written yet.
+        partial: Vec<u8>,
+        /// Frames sent before connecting, which are sent once connected.
+        queued: Vec<Vec<u8>>,
+        notifications: Option<UnboundedSender<Vec<u8>>>,
+        /// Whether the hub can be written to, which it can't after [`MockTransport::close`] until it's connected again.
+        linked: bool,
+    }
+
+    impl MockState {
+        /// Starts sending the bytes from the hub to a new stream, along with any frames that were queued.
+        fn link(&mut self) -> ByteStream {
+            let (tx, mut rx) = mpsc::unbounded_channel();
+            for frame in self.queued.drain(..) {
+                let _ = tx.send(frame);
+            }
+            self.notifications = Some(tx);
+            self.linked = true;
+            futures::stream::poll_fn(move |cx| rx.poll_recv(cx)).boxed()
+        }
+
+        fn send(&mut self, frame: Vec<u8>) {
+            if let Some(tx) = &self.notifications
+                && tx.send(frame.clone()).is_ok()
+            {
+                return;
+            }
+            self.queued.push(frame);
+        }
+    }
+
+    impl Default for MockTransport {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl MockTransport {
+        /// Creates a mock of a SPIKE Prime, which already expects the [`RxMessage::InfoRequest`] sent when connecting.
+        pub fn new() -> Self {
+            let mock = Self {
+                state: Arc::new(Mutex::new(MockState::default())),
+            };
+            mock.expect_connect();
+            mock
+        }
+
+        /// Expects the [`RxMessage::InfoRequest`] sent when connecting again, like by [`SpikeConnection::reconnect`](super::super::SpikeConnection::reconnect).
+        pub fn expect_connect(&self) -> &Self {
+            self.expect(RxMessage::InfoRequest, [INFO_RESPONSE.to_vec()])
+        }
+
+        fn state(&self) -> std::sync::MutexGuard<'_, MockState> {
+            self.state.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        /// Expects `request` to be written next, after any requests that are already expected, and responds to it with `responses`.
+        /// Each response is a serialized message, which is framed before being sent, like `vec![0x0d, 0x00]` for an acknowledged [`StartFileUploadResponse`](crate::connection::message::StartFileUploadResponse).
+        pub fn expect<'a>(
+            &self,
+            request: impl Into<RxMessage<'a>>,
+            responses: impl IntoIterator<Item = Vec<u8>>,
+        ) -> &Self {
+            let request = request.into().serialize();
+            let responses = responses.into_iter().collect();
+            self.state().script.push_back((request, responses));
+            self
+        }
+
+        /// Sends a serialized message from the hub without it being requested, like a [`ConsoleNotification`](crate::connection::message::ConsoleNotification).
+        pub fn send(&self, message: &[u8]) {
+            self.state().send(cobs::encode(message));
+        }
+
+        /// Makes the hub disconnect, like when it's turned off, by ending the bytes it sends. Writing to it fails with [`Error::Disconnected`] until it's connected again.
+        pub fn close(&self) {
+            let mut state = self.state();
+            state.notifications = None;
+            state.linked = false;
+        }
+
+        /// Returns the frames written so far, encoded, including their delimiters.
+        pub fn written(&self) -> Vec<Vec<u8>> {
+            self.state().written.clone()
+        }
+
+        /// Returns how many expected requests haven't been written yet.
+        pub fn remaining(&self) -> usize {
+            self.state().script.len()
+        }
+    }
+
+    impl Transport for MockTransport {
+        fn connect(&mut self) -> BoxFuture<'_, Result<ByteStream>> {
+            async { Ok(self.state().link()) }.boxed()
+        }
+
+        fn write<'a>(
+            &'a self,
+            bytes: &'a [u8],
+            _write_type: WriteType,
+        ) -> BoxFuture<'a, Result<()>> {
+            async move {
+                let mut state = self.state();
+                if !state.linked {
+                    return Err(Error::Disconnected);
+                }
+                state.partial.extend_from_slice(bytes);
+                while let Some(end) = state.partial.iter().position(|b| *b == cobs::DELIMITER) {
+                    let frame: Vec<u8> = state.partial.drain(..=end).collect();
+                    let message = cobs::decode(&frame)?;
+                    state.written.push(frame);
+
+                    let Some((expected, responses)) = state.script.pop_front() else {
+                        panic!("unexpected request {message:02x?}, nothing more was expected");
+                    };
+                    assert_eq!(message, expected, "unexpected request");
+                    for response in responses {
+                        state.send(cobs::encode(&response));
+                    }
+                }
+                Ok(())
+            }
+            .boxed()
+        }
+
+        fn relink(&self) -> BoxFuture<'_, Result<ByteStream>> {
+            async { Ok(self.state().link()) }.boxed()
+        }
+
+        fn disconnect(&mut self) -> BoxFuture<'_, Result<()>> {
+            async {
+                self.close();
+                Ok(())
+            }
+            .boxed()
+        }
+    }
+}
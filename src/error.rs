@@ -1,26 +1,74 @@
 //! Module for errors within the `spike-prime` crate.
 
-use std::{error, fmt::Display, io};
+use std::{convert::Infallible, error, fmt::Display, io, string::FromUtf8Error};
 
 /// Errors produced by `spike-prime`
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
     /// Errors from the `blteplug` crate
     BluetoothError(btleplug::Error),
     /// I/O errors
     Io(io::Error),
+    /// Produced when a string received from the device isn't valid UTF-8.
+    InvalidUtf8(FromUtf8Error),
     /// Produced when a device is connected to that isn't a SPIKE Prime. This error is pretty rare.
     BadDevice,
     /// Produced when a message is received from the device that isn't known in the SPIKE Prime protocol. Also pretty rare.
-    UnknownMessage,
+    /// Holds the ID of the message. Unknown devices in a [`DeviceNotification`](crate::connection::message::DeviceNotification) aren't errors, see [`DeviceMessage::Unknown`](crate::connection::message::DeviceMessage::Unknown).
+    UnknownMessage(u8),
     /// Produced when a message is received from the device, when a different message should have been sent.
-    WrongMessage,
+    /// Holds the names of the expected message and the one that was received instead.
+    WrongMessage {
+        expected: &'static str,
+        got: &'static str,
+    },
     /// Produced when a message is attempted to be sent that is larger than the max message size.
     OversizedMessage,
+    /// Produced when the framing of a message received from the device is invalid, like when part of it was lost.
+    MalformedFrame,
+    /// Produced when a message is received that ends before all of its fields could be read.
+    TruncatedMessage,
     /// Produced when a message is received that is supposed to contain an enumeration, but the value of the enumeration is not valid.
     InvalidEnumValue { enum_name: &'static str, value: u8 },
     /// Produced when a message is "Not Acknowledged" by the device.
     NotAcknowledged(&'static str, Option<usize>),
+    /// Produced when the connection to the device has been lost.
+    Disconnected,
+    /// Produced when the device stops acknowledging chunks during a transfer. Contains the index of the chunk that wasn't acknowledged.
+    UploadStalled { chunk_index: usize },
+    /// Produced when the hub doesn't respond in time.
+    Timeout,
+    /// Produced when no SPIKE Prime is found while scanning before the timeout.
+    ScanTimeout,
+    /// Produced when connecting to a SPIKE Prime takes too long. See [`crate::SpikePrime::connect_with_timeout`].
+    ConnectTimeout,
+    /// Produced when a program slot outside of 0-19 is used. See [`crate::connection::message::ProgramSlot`].
+    InvalidSlot(u8),
+    /// Produced when a program name is too long for the hub to store. See [`crate::connection::message::validate_program_name`].
+    FilenameTooLong(String),
+    /// Produced when something is asked of the hub that can't be done, like reading a program back off of it, which the SPIKE Prime protocol has no way to do.
+    Unsupported,
+    /// Produced when the hub didn't store a setting that was sent to it. See [`crate::connection::SpikeConnection::set_hub_name_verified`].
+    VerificationFailed { expected: String, actual: String },
+    /// Produced when one of the programs given to [`crate::connection::SpikeConnection::upload_programs`] couldn't be uploaded.
+    UploadFailed {
+        slot: u8,
+        name: String,
+        error: Box<Error>,
+    },
+    /// Produced when a slot couldn't be cleared before uploading to it, like by [`crate::connection::SpikeConnection::deploy_and_run`], or after cancelling an upload.
+    ClearFailed { slot: u8, error: Box<Error> },
+    /// Produced when [`crate::connection::SpikeConnection::deploy_and_run`] uploaded a program, but couldn't start it.
+    StartFailed { slot: u8, error: Box<Error> },
+    /// Produced when a hub name is too long for the hub to store. Contains the length of the name in bytes. See [`crate::connection::message::validate_hub_name`].
+    NameTooLong(usize),
+    /// Produced when the hub is given an empty name.
+    EmptyName,
+    /// Produced when the Bluetooth adapter asked for doesn't exist. See [`crate::SpikePrime::adapter_by_name`].
+    AdapterNotFound(String),
+    /// Produced when an upload is cancelled. See [`crate::connection::SpikeConnection::upload_program_cancellable`].
+    Cancelled,
 }
 
 impl Display for Error {
@@ -28,9 +76,16 @@ impl Display for Error {
         match self {
             Error::BluetoothError(e) => write!(f, "{e}"),
             Error::Io(e) => write!(f, "{e}"),
+            Error::InvalidUtf8(e) => write!(f, "device sent invalid UTF-8: {e}"),
             Error::BadDevice => write!(f, "tried to connect to a device that isn't a SPIKE Prime"),
-            Error::UnknownMessage => write!(f, "tried to deserialize an invalid packet"),
-            Error::WrongMessage => write!(f, "device sent incorrect packet"),
+            Error::UnknownMessage(id) => {
+                write!(f, "device sent a message with unknown ID 0x{id:02x}")
+            }
+            Error::WrongMessage { expected, got } => {
+                write!(f, "expected {expected} from the device, but it sent {got}")
+            }
+            Error::MalformedFrame => write!(f, "device sent a malformed frame"),
+            Error::TruncatedMessage => write!(f, "device sent a message that was too short"),
             Error::OversizedMessage => {
                 write!(f, "tried to send a message over the max message size")
             }
@@ -46,15 +101,63 @@ impl Display for Error {
                     "".to_string()
                 }
             ),
+            Error::Disconnected => write!(f, "device disconnected"),
+            Error::UploadStalled { chunk_index } => {
+                write!(
+                    f,
+                    "device stopped acknowledging chunks at chunk {chunk_index}"
+                )
+            }
+            Error::FilenameTooLong(name) => {
+                write!(f, "program name {name:?} is longer than 31 bytes")
+            }
+            Error::Timeout => write!(f, "timed out waiting for the device to respond"),
+            Error::ScanTimeout => write!(f, "no SPIKE Prime found before timing out"),
+            Error::ConnectTimeout => write!(f, "timed out connecting to the device"),
+            Error::InvalidSlot(slot) => write!(f, "program slot {slot} is not between 0 and 19"),
+            Error::Unsupported => write!(f, "the SPIKE Prime protocol doesn't support this"),
+            Error::UploadFailed { slot, name, error } => {
+                write!(
+                    f,
+                    "failed to upload {name:?} to program slot {slot}: {error}"
+                )
+            }
+            Error::ClearFailed { slot, error } => {
+                write!(f, "failed to clear program slot {slot}: {error}")
+            }
+            Error::StartFailed { slot, error } => {
+                write!(f, "failed to start the program in slot {slot}: {error}")
+            }
+            Error::NameTooLong(len) => {
+                write!(f, "hub name is {len} bytes long, but can only be 29")
+            }
+            Error::EmptyName => write!(f, "hub name is empty"),
+            Error::Cancelled => write!(f, "the upload was cancelled"),
+            Error::AdapterNotFound(adapter) => write!(f, "Bluetooth adapter {adapter} not found"),
+            Error::VerificationFailed { expected, actual } => {
+                write!(
+                    f,
+                    "expected the device to have {expected:?}, but it has {actual:?}"
+                )
+            }
         }
     }
 }
 
 impl error::Error for Error {}
 
+impl From<Infallible> for Error {
+    fn from(value: Infallible) -> Self {
+        match value {}
+    }
+}
+
 impl From<btleplug::Error> for Error {
     fn from(e: btleplug::Error) -> Self {
-        Self::BluetoothError(e)
+        match e {
+            btleplug::Error::NotConnected => Self::Disconnected,
+            e => Self::BluetoothError(e),
+        }
     }
 }
 
@@ -64,5 +167,11 @@ impl From<io::Error> for Error {
     }
 }
 
+impl From<FromUtf8Error> for Error {
+    fn from(e: FromUtf8Error) -> Self {
+        Self::InvalidUtf8(e)
+    }
+}
+
 /// Result type using [`Error`] for convenience.
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -0,0 +1,221 @@
+//! Module for controlling multiple SPIKE Primes at once.
+
+use btleplug::platform::{Adapter, PeripheralId};
+use futures::{StreamExt, future::BoxFuture};
+
+use crate::{
+    SCAN_TIMEOUT, SpikePrime,
+    connection::{SpikeConnection, UploadReport},
+    error::*,
+};
+
+const DEFAULT_MAX_CONCURRENCY: usize = 4;
+
+/// Identifies a hub in a [`HubGroup`].
+pub type HubId = PeripheralId;
+
+/// A group of connected SPIKE Primes that can be controlled all at once, like a classroom of hubs.
+///
+/// Operations on the whole group run on several hubs concurrently, and return the result for each hub along with its [`HubId`], in the order the hubs were added.
+/// To avoid overwhelming the Bluetooth adapter, only a few hubs are communicated with at a time, see [`HubGroup::set_max_concurrency`].
+#[derive(Debug)]
+pub struct HubGroup {
+    hubs: Vec<SpikeConnection>,
+    ids: Vec<HubId>,
+    max_concurrency: usize,
+}
+
+impl Default for HubGroup {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HubGroup {
+    /// Creates an empty [`HubGroup`].
+    pub fn new() -> Self {
+        Self {
+            hubs: Vec::new(),
+            ids: Vec::new(),
+            max_concurrency: DEFAULT_MAX_CONCURRENCY,
+        }
+    }
+
+    /// Adds a connected hub to the group.
+    /// Hubs are identified by their Bluetooth id, so this returns [`Error::Unsupported`] if the hub isn't connected over Bluetooth.
+    pub fn add(&mut self, connection: SpikeConnection) -> Result<()> {
+        let id = connection.peripheral_id().ok_or(Error::Unsupported)?;
+        self.ids.push(id);
+        self.hubs.push(connection);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.hubs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hubs.is_empty()
+    }
+
+    /// Returns the connections to the hubs in the group.
+    pub fn connections(&self) -> &[SpikeConnection] {
+        &self.hubs
+    }
+
+    /// A mutable version of [`HubGroup::connections`].
+    pub fn connections_mut(&mut self) -> &mut [SpikeConnection] {
+        &mut self.hubs
+    }
+
+    /// Sets how many hubs are communicated with at a time. Defaults to 4.
+    pub fn set_max_concurrency(&mut self, max_concurrency: usize) {
+        self.max_concurrency = max_concurrency.max(1);
+    }
+
+    /// Uploads a python program to every hub, see [`SpikeConnection::upload_program`].
+    pub async fn upload_to_all(
+        &mut self,
+        slot: u8,
+        name: &str,
+        code: &str,
+    ) -> Vec<(HubId, Result<UploadReport>)> {
+        self.for_each(|hub| Box::pin(hub.upload_program(slot, name.to_string(), code.to_string())))
+            .await
+    }
+
+    /// Starts a program on every hub, see [`SpikeConnection::start_program`].
+    pub async fn start_all(&mut self, slot: u8) -> Vec<(HubId, Result<()>)> {
+        self.for_each(|hub| Box::pin(hub.start_program(slot))).await
+    }
+
+    /// Returns the battery percentage of every hub, from their last device notification.
+    /// Device notifications must be enabled for this to return Some, see [`SpikeConnection::enable_device_notifications`].
+    pub async fn collect_battery(&self) -> Vec<(HubId, Option<u8>)> {
+        let mut batteries = Vec::new();
+        for (hub, id) in self.hubs.iter().zip(&self.ids) {
+            let battery = hub.device_notification().await.and_then(|n| n.battery());
+            batteries.push((id.clone(), battery));
+        }
+        batteries
+    }
+
+    /// Runs an operation on every hub, a few at a time.
+    async fn for_each<T>(
+        &mut self,
+        f: impl FnMut(&mut SpikeConnection) -> BoxFuture<'_, T>,
+    ) -> Vec<(HubId, T)> {
+        let ids = self.ids.clone();
+        let results: Vec<_> = futures::stream::iter(self.hubs.iter_mut().map(f))
+            .buffered(self.max_concurrency)
+            .collect()
+            .await;
+        ids.into_iter().zip(results).collect()
+    }
+}
+
+/// A set of connected SPIKE Primes identified by name, like the hubs on a competition robot.
+///
+/// This is a [`HubGroup`] that also keeps the name of each hub, so it talks to the same number of hubs at a time. Only errors are returned from operations on the whole fleet.
+#[derive(Debug, Default)]
+pub struct SpikeFleet {
+    group: HubGroup,
+    /// The name of each hub, in the same order as the group.
+    names: Vec<String>,
+}
+
+impl SpikeFleet {
+    /// Scans for and connects to the hubs called `names`, a few at a time like [`HubGroup`].
+    /// Returns the hubs that were connected to, along with the names of the ones that couldn't be and why. Hubs that aren't found within 10 seconds get [`Error::ScanTimeout`].
+    ///
+    /// All the hubs are looked for in one scan, so an error is only returned if scanning fails.
+    pub async fn connect_all(
+        adapter: &Adapter,
+        names: &[&str],
+    ) -> Result<(Self, Vec<(String, Error)>)> {
+        let mut hubs = SpikePrime::scan(adapter).await?;
+        let mut missing = names.to_vec();
+        let mut found = Vec::new();
+        let _ = tokio::time::timeout(SCAN_TIMEOUT, async {
+            while !missing.is_empty()
+                && let Some(hub) = hubs.next().await
+            {
+                let Some(name) = hub.name().await else {
+                    continue;
+                };
+                if let Some(index) = missing.iter().position(|n| *n == name) {
+                    missing.remove(index);
+                    found.push((name, hub));
+                }
+            }
+        })
+        .await;
+
+        let results: Vec<_> = futures::stream::iter(found)
+            .map(async |(name, hub)| (name, hub.connect().await))
+            .buffered(DEFAULT_MAX_CONCURRENCY)
+            .collect()
+            .await;
+
+        let mut fleet = Self::default();
+        let mut errors = Vec::new();
+        for (name, result) in results {
+            if let Err(e) = result.and_then(|connection| fleet.insert(name.clone(), connection)) {
+                errors.push((name, e));
+            }
+        }
+        errors.extend(
+            missing
+                .into_iter()
+                .map(|name| (name.to_string(), Error::ScanTimeout)),
+        );
+        Ok((fleet, errors))
+    }
+
+    /// Adds a connected hub to the fleet under `name`, replacing any hub that already had it.
+    /// Returns [`Error::Unsupported`] if the hub isn't connected over Bluetooth, like [`HubGroup::add`].
+    pub fn insert(&mut self, name: String, connection: SpikeConnection) -> Result<()> {
+        self.group.add(connection)?;
+        if let Some(index) = self.names.iter().position(|n| *n == name) {
+            self.names.remove(index);
+            self.group.hubs.remove(index);
+            self.group.ids.remove(index);
+        }
+        self.names.push(name);
+        Ok(())
+    }
+
+    /// Returns the connection to the hub called `name`.
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut SpikeConnection> {
+        let index = self.names.iter().position(|n| n == name)?;
+        self.group.hubs.get_mut(index)
+    }
+
+    /// Returns the names of the hubs in the fleet.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.names.iter().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.group.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.group.is_empty()
+    }
+
+    /// Sets how many hubs are communicated with at a time, see [`HubGroup::set_max_concurrency`].
+    pub fn set_max_concurrency(&mut self, max_concurrency: usize) {
+        self.group.set_max_concurrency(max_concurrency);
+    }
+
+    /// Starts a program on every hub, see [`HubGroup::start_all`]. Returns the hubs that failed to start it.
+    pub async fn broadcast_start(&mut self, slot: u8) -> Vec<(String, Error)> {
+        let results = self.group.start_all(slot).await;
+        self.names
+            .iter()
+            .zip(results)
+            .filter_map(|(name, (_, result))| Some((name.clone(), result.err()?)))
+            .collect()
+    }
+}
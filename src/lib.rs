@@ -1,7 +1,7 @@
-use std::pin::Pin;
+use std::{pin::Pin, time::Duration};
 
 use btleplug::api::{Central as _, CentralEvent, Peripheral as _, ScanFilter};
-use btleplug::platform::{Adapter, Peripheral};
+use btleplug::platform::{Adapter, Peripheral, PeripheralId};
 use futures::{Stream, StreamExt};
 use uuid::Uuid;
 
@@ -9,12 +9,20 @@ pub mod error;
 
 pub mod connection;
 
+pub mod group;
+
+pub mod profile;
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+
 pub mod prelude {
     pub use crate::SpikePrime;
     pub use crate::connection::SpikeConnection;
     pub use crate::error::*;
     pub use btleplug::{api::Manager as _, platform::Manager};
 }
+use connection::{SpikeConnectionConfig, message::DeviceType};
 use prelude::*;
 
 /// Represents a SPIKE Prime device, before it has been connected to.
@@ -25,7 +33,95 @@ const PRIME_SERVICE: Uuid = Uuid::from_bytes([
     0x00, 0x00, 0xFD, 0x02, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0x80, 0x5F, 0x9B, 0x34, 0xFB,
 ]);
 
+/// How long [`SpikePrime::scan_first`] waits for a SPIKE Prime to be found.
+pub(crate) const SCAN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long each attempt of [`SpikePrime::connect`] can take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+/// How many times [`SpikePrime::connect`] tries to connect.
+const CONNECT_ATTEMPTS: usize = 3;
+
+/// Returns the first hub from a scan, or [`Error::ScanTimeout`] if it takes longer than `timeout`.
+async fn first_within(
+    mut hubs: Pin<Box<dyn Stream<Item = SpikePrime> + Send + '_>>,
+    timeout: Duration,
+) -> Result<SpikePrime> {
+    tokio::time::timeout(timeout, hubs.next())
+        .await
+        .ok()
+        .flatten()
+        .ok_or(Error::ScanTimeout)
+}
+
+/// A Bluetooth adapter found by [`SpikePrime::list_adapters`], for letting a user pick one.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AdapterInfo {
+    /// The adapter's position in the list, for [`SpikePrime::adapter_by_index`].
+    pub index: usize,
+    /// The adapter's info, like `hci0 (usb:v1D6Bp0246d0540)` on Linux, for [`SpikePrime::adapter_by_name`].
+    pub name: String,
+}
+
+/// Returns whether `info`, from [`Central::adapter_info`](btleplug::api::Central::adapter_info), is the info of the adapter called `name`. See [`SpikePrime::adapter_by_name`].
+fn is_adapter_named(info: &str, name: &str) -> bool {
+    info.eq_ignore_ascii_case(name)
+        || info
+            .split_whitespace()
+            .next()
+            .is_some_and(|id| id.eq_ignore_ascii_case(name))
+}
+
 impl SpikePrime {
+    /// Lists the Bluetooth adapters of the computer, in the order the system lists them.
+    pub async fn list_adapters(manager: &Manager) -> Result<Vec<AdapterInfo>> {
+        let mut adapters = Vec::new();
+        for (index, adapter) in manager.adapters().await?.into_iter().enumerate() {
+            adapters.push(AdapterInfo {
+                index,
+                name: adapter.adapter_info().await?,
+            });
+        }
+        Ok(adapters)
+    }
+
+    /// Returns the Bluetooth adapter called `name`, ignoring case, like `hci0` on Linux.
+    /// `name` is compared to the whole [`Central::adapter_info`](btleplug::api::Central::adapter_info), or to the part of it before the first space, like `hci0` in `hci0 (usb:v1D6Bp0246d0540)`.
+    /// Returns [`Error::AdapterNotFound`] if there isn't one.
+    pub async fn adapter_by_name(manager: &Manager, name: &str) -> Result<Adapter> {
+        Self::find_adapter(manager, name, |info| is_adapter_named(info, name)).await
+    }
+
+    /// Returns the first Bluetooth adapter whose [`Central::adapter_info`](btleplug::api::Central::adapter_info) starts with `prefix`.
+    /// Unlike [`SpikePrime::adapter_by_name`], `hci1` matches `hci10` too, so this is only for when any matching adapter will do.
+    /// Returns [`Error::AdapterNotFound`] if there isn't one.
+    pub async fn adapter_by_prefix(manager: &Manager, prefix: &str) -> Result<Adapter> {
+        Self::find_adapter(manager, prefix, |info| info.starts_with(prefix)).await
+    }
+
+    async fn find_adapter(
+        manager: &Manager,
+        name: &str,
+        matches: impl Fn(&str) -> bool,
+    ) -> Result<Adapter> {
+        for adapter in manager.adapters().await? {
+            if matches(&adapter.adapter_info().await?) {
+                return Ok(adapter);
+            }
+        }
+        Err(Error::AdapterNotFound(format!("{name:?}")))
+    }
+
+    /// Returns the Bluetooth adapter at `index`, in the order the system lists them. Use 0 for the only adapter on most computers.
+    /// Returns [`Error::AdapterNotFound`] if there isn't one.
+    pub async fn adapter_by_index(manager: &Manager, index: usize) -> Result<Adapter> {
+        manager
+            .adapters()
+            .await?
+            .into_iter()
+            .nth(index)
+            .ok_or_else(|| Error::AdapterNotFound(format!("number {index}")))
+    }
+
     /// Scans bluetooth devices, looking for a SPIKE Prime, using an [`Adapter`].
     pub async fn scan<'a>(
         adapter: &'a Adapter,
@@ -50,17 +146,160 @@ impl SpikePrime {
     }
 
     /// Scans bluetooth devices, returning the first SPIKE Prime it finds.
+    /// Returns [`Error::ScanTimeout`] if none is found within 10 seconds, see [`SpikePrime::scan_first_timeout`] to change that.
     pub async fn scan_first(adapter: &Adapter) -> Result<Self> {
-        Ok(Self::scan(adapter).await?.next().await.unwrap())
+        Self::scan_first_timeout(adapter, SCAN_TIMEOUT).await
+    }
+
+    /// Like [`SpikePrime::scan_first`], but waits up to `timeout` for a SPIKE Prime to be found.
+    pub async fn scan_first_timeout(adapter: &Adapter, timeout: Duration) -> Result<Self> {
+        first_within(Self::scan(adapter).await?, timeout).await
+    }
+
+    /// Scans bluetooth devices, looking for SPIKE Primes called `name`. See [`SpikePrime::name`].
+    pub async fn scan_by_name<'a>(
+        adapter: &'a Adapter,
+        name: &'a str,
+    ) -> Result<Pin<Box<dyn Stream<Item = SpikePrime> + Send + 'a>>> {
+        let hubs = Self::scan(adapter).await?.filter_map(async move |hub| {
+            (hub.name().await.as_deref() == Some(name)).then_some(hub)
+        });
+
+        Ok(Box::pin(hubs))
+    }
+
+    /// Scans bluetooth devices, returning the first SPIKE Prime called `name`.
+    /// Returns [`Error::ScanTimeout`] if none is found within 10 seconds.
+    pub async fn scan_first_by_name(adapter: &Adapter, name: &str) -> Result<Self> {
+        first_within(Self::scan_by_name(adapter, name).await?, SCAN_TIMEOUT).await
+    }
+
+    /// Scans bluetooth devices for `window`, returning the SPIKE Prime with the strongest signal, which is usually the closest one.
+    /// Returns [`Error::ScanTimeout`] if none were found.
+    pub async fn scan_strongest(adapter: &Adapter, window: Duration) -> Result<Self> {
+        let mut hubs = Self::scan(adapter).await?;
+        let mut found = Vec::new();
+        let _ = tokio::time::timeout(window, async {
+            while let Some(hub) = hubs.next().await {
+                found.push(hub);
+            }
+        })
+        .await;
+
+        let mut strongest = None;
+        for hub in found {
+            let rssi = hub.rssi().await.unwrap_or(i16::MIN);
+            if strongest.as_ref().is_none_or(|(best, _)| rssi > *best) {
+                strongest = Some((rssi, hub));
+            }
+        }
+        strongest.map(|(_, hub)| hub).ok_or(Error::ScanTimeout)
     }
 
     /// Connects to a [`SpikePrime`] by returning a [`SpikeConnection`].
+    /// Each attempt to connect can take 20 seconds, and it's tried 3 times, see [`SpikePrime::connect_with_retries`].
     pub async fn connect(self) -> Result<SpikeConnection> {
-        SpikeConnection::new(self.0).await
+        self.connect_with_timeout(CONNECT_TIMEOUT).await
+    }
+
+    /// Like [`SpikePrime::connect`], but each attempt to connect can only take `timeout`.
+    pub async fn connect_with_timeout(self, timeout: Duration) -> Result<SpikeConnection> {
+        self.connect_with_retries(timeout, CONNECT_ATTEMPTS).await
+    }
+
+    /// Connects to a [`SpikePrime`], trying up to `attempts` times if connecting takes longer than `timeout` or the Bluetooth connection fails.
+    /// Returns [`Error::ConnectTimeout`] if the last attempt timed out.
+    ///
+    /// This is useful for setups that have to recover on their own, since a hub in a bad state can keep a connection from ever finishing.
+    pub async fn connect_with_retries(
+        self,
+        timeout: Duration,
+        attempts: usize,
+    ) -> Result<SpikeConnection> {
+        self.connect_inner(
+            &[DeviceType::SpikePrime],
+            timeout,
+            attempts,
+            SpikeConnectionConfig::default(),
+        )
+        .await
+    }
+
+    /// Like [`SpikePrime::connect`], but with settings other than the defaults, like bigger buffers for a program that prints a lot. See [`SpikeConnectionConfig`].
+    pub async fn connect_with_config(
+        self,
+        config: SpikeConnectionConfig,
+    ) -> Result<SpikeConnection> {
+        self.connect_inner(
+            &[DeviceType::SpikePrime],
+            CONNECT_TIMEOUT,
+            CONNECT_ATTEMPTS,
+            config,
+        )
+        .await
+    }
+
+    /// Like [`SpikePrime::connect`], but also connects to other kinds of hubs, like SPIKE Essentials.
+    /// Returns [`Error::BadDevice`] if the hub isn't one of `device_types`.
+    pub async fn connect_allowing(self, device_types: &[DeviceType]) -> Result<SpikeConnection> {
+        self.connect_inner(
+            device_types,
+            CONNECT_TIMEOUT,
+            CONNECT_ATTEMPTS,
+            SpikeConnectionConfig::default(),
+        )
+        .await
+    }
+
+    async fn connect_inner(
+        self,
+        device_types: &[DeviceType],
+        timeout: Duration,
+        attempts: usize,
+        config: SpikeConnectionConfig,
+    ) -> Result<SpikeConnection> {
+        let mut error = Error::ConnectTimeout;
+        for _ in 0..attempts.max(1) {
+            let connect = SpikeConnection::new(self.0.clone(), device_types, config);
+            error = match tokio::time::timeout(timeout, connect).await {
+                Ok(Ok(connection)) => return Ok(connection),
+                Ok(Err(e @ (Error::BluetoothError(_) | Error::Disconnected))) => e,
+                Ok(Err(e)) => return Err(e),
+                Err(_) => Error::ConnectTimeout,
+            };
+            // Start the next attempt from scratch
+            let _ = self.0.disconnect().await;
+        }
+        Err(error)
+    }
+
+    /// Returns the Bluetooth id of the [`SpikePrime`], which stays the same after connecting to it. See [`SpikeConnection::peripheral_id`].
+    pub fn id(&self) -> PeripheralId {
+        self.0.id()
     }
 
     /// Finds the name of a [`SpikePrime`] without connecting to it.
     pub async fn name(&self) -> Option<String> {
         self.0.properties().await.ok()??.local_name
     }
+
+    /// Finds the signal strength of a [`SpikePrime`] in dBm without connecting to it. Higher is stronger.
+    pub async fn rssi(&self) -> Option<i16> {
+        self.0.properties().await.ok()??.rssi
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adapter_names() {
+        let info = "hci10 (usb:v1D6Bp0246d0540)";
+        assert!(is_adapter_named(info, "hci10"));
+        assert!(is_adapter_named(info, "HCI10"));
+        assert!(is_adapter_named(info, info));
+        assert!(!is_adapter_named(info, "hci1"));
+        assert!(!is_adapter_named(info, ""));
+    }
 }
@@ -0,0 +1,103 @@
+//! Module for setting up hubs from a profile, like when provisioning a classroom of hubs.
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{connection::SpikeConnection, error::*};
+
+/// Settings to apply to a hub all at once with [`SpikeConnection::apply_profile`]. Every setting is optional, and settings that aren't set are left alone.
+///
+/// With the `serde` feature, this can be deserialized from a config file. Missing fields use their defaults.
+///
+/// There's no idle timeout setting, since the SPIKE Prime protocol has no message for changing how long the hub waits before turning itself off.
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default))]
+pub struct HubProfile {
+    /// The name to give the hub
+    pub name: Option<String>,
+    /// Programs to upload to the hub. The slots are cleared before any programs are uploaded to them.
+    pub programs: Vec<ProfileProgram>,
+    /// The interval to send device notifications at in milliseconds, or 0 to disable them
+    pub device_notification_interval: Option<u16>,
+}
+
+/// A program to upload as part of a [`HubProfile`].
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ProfileProgram {
+    pub slot: u8,
+    pub name: String,
+    pub code: String,
+}
+
+/// A step taken by [`SpikeConnection::apply_profile`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ProfileStep {
+    SetName,
+    ClearSlot(u8),
+    UploadProgram(u8),
+    SetDeviceNotificationInterval,
+}
+
+/// The result of each step taken by [`SpikeConnection::apply_profile`], in the order they were taken.
+#[derive(Debug)]
+pub struct ProfileReport {
+    pub steps: Vec<(ProfileStep, Result<()>)>,
+}
+
+impl ProfileReport {
+    /// Returns whether every step succeeded.
+    pub fn is_success(&self) -> bool {
+        self.steps.iter().all(|(_, result)| result.is_ok())
+    }
+}
+
+impl SpikeConnection {
+    /// Applies every setting in a [`HubProfile`] to the hub. The hub is renamed first, then the program slots are cleared, the programs are uploaded, and finally device notifications are set up.
+    ///
+    /// A failed step doesn't stop the rest from being applied, so check the returned [`ProfileReport`] to see which steps succeeded. This only returns an error if the hub disconnects.
+    /// Clearing a slot that's already empty isn't counted as a failure.
+    pub async fn apply_profile(&mut self, profile: HubProfile) -> Result<ProfileReport> {
+        let mut steps = Vec::new();
+
+        if let Some(name) = &profile.name {
+            steps.push((ProfileStep::SetName, self.set_hub_name(name).await));
+        }
+
+        let mut slots: Vec<_> = profile.programs.iter().map(|p| p.slot).collect();
+        slots.sort_unstable();
+        slots.dedup();
+        for slot in slots {
+            let result = match self.clear_program_slot(slot).await {
+                Err(Error::NotAcknowledged(..)) => Ok(()),
+                result => result,
+            };
+            steps.push((ProfileStep::ClearSlot(slot), result));
+        }
+
+        for program in profile.programs {
+            let slot = program.slot;
+            let result = self
+                .upload_program(slot, program.name, program.code)
+                .await
+                .map(|_| ());
+            steps.push((ProfileStep::UploadProgram(slot), result));
+        }
+
+        if let Some(interval) = profile.device_notification_interval {
+            let result = self
+                .enable_device_notifications_with_interval(interval)
+                .await;
+            steps.push((ProfileStep::SetDeviceNotificationInterval, result));
+        }
+
+        if steps
+            .iter()
+            .any(|(_, result)| matches!(result, Err(Error::Disconnected)))
+        {
+            return Err(Error::Disconnected);
+        }
+        Ok(ProfileReport { steps })
+    }
+}
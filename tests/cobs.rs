@@ -0,0 +1,45 @@
+use proptest::prelude::*;
+use spike_prime::{connection::cobs, error::Error};
+
+proptest! {
+    #[test]
+    fn round_trip(data in proptest::collection::vec(any::<u8>(), 0..600)) {
+        prop_assert_eq!(cobs::decode(&cobs::encode(&data)).unwrap(), data);
+    }
+
+    #[test]
+    fn only_ends_with_delimiter(data in proptest::collection::vec(any::<u8>(), 0..600)) {
+        let frame = cobs::encode(&data);
+        prop_assert_eq!(frame.iter().position(|b| *b == cobs::DELIMITER), Some(frame.len() - 1));
+    }
+
+    #[test]
+    fn high_priority_round_trip(data in proptest::collection::vec(any::<u8>(), 0..600)) {
+        let mut frame = vec![cobs::HIGH_PRIORITY];
+        frame.extend(cobs::encode(&data));
+        prop_assert_eq!(cobs::decode(&frame).unwrap(), data);
+    }
+
+    #[test]
+    fn garbage_does_not_panic(data in proptest::collection::vec(any::<u8>(), 0..600)) {
+        let _ = cobs::decode(&data);
+    }
+}
+
+#[test]
+fn known_frames() {
+    assert_eq!(cobs::encode(&[]), [0x00, 0x02]);
+    assert_eq!(cobs::encode(&[0x00]), [0x00, 0x00, 0x02]);
+    assert_eq!(
+        cobs::encode(&[0x01, 0x02, 0x03]),
+        [0x54, 0xa8, 0x07, 0x00, 0x02]
+    );
+}
+
+#[test]
+fn malformed_frames() {
+    assert!(matches!(cobs::decode(&[]), Err(Error::MalformedFrame)));
+    assert!(matches!(cobs::decode(&[0x02]), Err(Error::MalformedFrame)));
+    // Missing the delimiter
+    assert!(matches!(cobs::decode(&[0x00]), Err(Error::MalformedFrame)));
+}
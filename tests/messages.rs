@@ -0,0 +1,490 @@
+use spike_prime::{connection::message::*, error::Error};
+
+#[test]
+fn program_slot_range() {
+    assert_eq!(ProgramSlot::new(0).map(ProgramSlot::get), Some(0));
+    assert_eq!(ProgramSlot::new(19).map(ProgramSlot::get), Some(19));
+    assert_eq!(ProgramSlot::new(20), None);
+    assert!(matches!(
+        ProgramSlot::try_from(200),
+        Err(Error::InvalidSlot(200))
+    ));
+}
+
+#[test]
+fn program_flow_request() {
+    let start = ProgramFlowRequest {
+        program_action: ProgramAction::Start,
+        program_slot: 3,
+    };
+    assert_eq!(start.serialize(), [0x1e, 0x00, 3]);
+
+    let stop = ProgramFlowRequest {
+        program_action: ProgramAction::Stop,
+        program_slot: 3,
+    };
+    assert_eq!(stop.serialize(), [0x1e, 0x01, 3]);
+}
+
+#[test]
+fn program_name_length() {
+    assert!(validate_program_name("program.py").is_ok());
+    assert!(validate_program_name(&"a".repeat(MAX_PROGRAM_NAME_LEN)).is_ok());
+    assert!(matches!(
+        validate_program_name(&"a".repeat(40)),
+        Err(Error::FilenameTooLong(_))
+    ));
+    // 8 emoji are 32 bytes, so the last one would be split
+    assert!(matches!(
+        validate_program_name(&"🚀".repeat(8)),
+        Err(Error::FilenameTooLong(_))
+    ));
+}
+
+#[test]
+fn hub_name_length() {
+    assert!(validate_hub_name("hub").is_ok());
+    assert!(validate_hub_name(&"a".repeat(MAX_HUB_NAME_LEN)).is_ok());
+    assert!(matches!(
+        validate_hub_name(&"a".repeat(40)),
+        Err(Error::NameTooLong(40))
+    ));
+    // 7 emoji are 28 bytes, and an 8th would be split
+    assert!(validate_hub_name(&"🚀".repeat(7)).is_ok());
+    assert!(matches!(
+        validate_hub_name(&"🚀".repeat(8)),
+        Err(Error::NameTooLong(32))
+    ));
+    assert!(matches!(validate_hub_name(""), Err(Error::EmptyName)));
+
+    // Names are never cut off mid-character when serialized
+    let request = SetHubNameRequest {
+        name: &"🚀".repeat(8),
+    }
+    .serialize();
+    assert!(std::str::from_utf8(&request[1..request.len() - 1]).is_ok());
+}
+
+#[test]
+fn unterminated_name() {
+    let mut name = vec![0x19];
+    name.extend_from_slice(&[b'a'; 64]);
+    name.push(0);
+    assert!(matches!(
+        TxMessage::deserialize(name.clone()),
+        Ok(TxMessage::GetHubNameResponse(_))
+    ));
+
+    name.insert(1, b'a');
+    assert!(matches!(
+        TxMessage::deserialize(name),
+        Err(Error::MalformedFrame)
+    ));
+}
+
+#[test]
+fn tunnel_message() {
+    let sent = TunnelMessage {
+        payload: &[1, 2, 3],
+    };
+    assert_eq!(sent.serialize(), [0x32, 3, 0, 1, 2, 3]);
+
+    let received = TxMessage::deserialize(vec![0x32, 3, 0, 1, 2, 3]).unwrap();
+    assert_eq!(
+        received,
+        TxMessage::TunnelMessage(TunnelNotification {
+            payload: vec![1, 2, 3]
+        })
+    );
+
+    assert!(matches!(
+        TxMessage::deserialize(vec![0x32, 3, 0, 1]),
+        Err(Error::TruncatedMessage)
+    ));
+}
+
+#[test]
+fn matrix_display() {
+    let mut pixels = [0; 25];
+    pixels[0] = 100;
+    pixels[6] = 50;
+    pixels[12] = 10;
+    let display = DeviceMessage::Device5x5MatrixDisplay(pixels)
+        .as_matrix_display()
+        .unwrap();
+
+    assert_eq!(display.as_grid()[1], [0, 50, 0, 0, 0]);
+    assert_eq!(display.to_string(), "#    \n o   \n  .  \n     \n     ");
+    assert_eq!(DeviceMessage::DeviceBattery(50).as_matrix_display(), None);
+}
+
+#[test]
+fn notification_snapshot() {
+    let notification = DeviceNotification {
+        payload: vec![
+            DeviceMessage::DeviceBattery(80),
+            DeviceMessage::DeviceDistanceSensor {
+                port: HubPort::C,
+                distance: 120,
+            },
+            DeviceMessage::DeviceForceSensor {
+                port: HubPort::E,
+                value: 5,
+                pressure: false,
+            },
+        ],
+    };
+
+    let ports = notification.port_map();
+    assert_eq!(ports.len(), 2);
+    assert_eq!(ports[&HubPort::C], &notification.payload[1]);
+    assert_eq!(notification.battery(), Some(80));
+    assert_eq!(notification.imu(), None);
+    assert_eq!(
+        notification.device(HubPort::E),
+        Some(&notification.payload[2])
+    );
+
+    let mut ports = Vec::new();
+    for message in &notification {
+        ports.push(message.port());
+    }
+    assert_eq!(ports, [None, Some(HubPort::C), Some(HubPort::E)]);
+    assert_eq!(notification.iter().count(), 3);
+    assert_eq!(
+        notification.into_iter().next(),
+        Some(DeviceMessage::DeviceBattery(80))
+    );
+}
+
+#[test]
+fn metric_keys() {
+    let notification = DeviceNotification {
+        payload: vec![
+            DeviceMessage::DeviceBattery(80),
+            DeviceMessage::DeviceImuValues {
+                up_face: HubFace::Top,
+                yaw_face: HubFace::Front,
+                yaw: 90,
+                pitch: 0,
+                roll: 0,
+                accelerometer_x: 0,
+                accelerometer_y: 0,
+                accelerometer_z: 0,
+                gyroscope_x: 0,
+                gyroscope_y: 0,
+                gyroscope_z: 0,
+            },
+            DeviceMessage::DeviceMotor {
+                port: HubPort::C,
+                motor_device_type: MotorDeviceType::Medium,
+                absolute_position: 0,
+                power: 0,
+                speed: 0,
+                position: 360,
+            },
+        ],
+    };
+
+    let metrics = notification.to_metrics();
+    let mut keys: Vec<_> = metrics.keys().map(String::as_str).collect();
+    keys.sort_unstable();
+    // Dashboards depend on these, so changing them is a breaking change
+    assert_eq!(
+        keys,
+        [
+            "battery",
+            "imu.accelerometer_x",
+            "imu.accelerometer_y",
+            "imu.accelerometer_z",
+            "imu.gyroscope_x",
+            "imu.gyroscope_y",
+            "imu.gyroscope_z",
+            "imu.pitch",
+            "imu.roll",
+            "imu.up_face",
+            "imu.yaw",
+            "imu.yaw_face",
+            "motor.C.absolute_position",
+            "motor.C.device_type",
+            "motor.C.position",
+            "motor.C.power",
+            "motor.C.speed",
+        ]
+    );
+    assert_eq!(metrics["battery"], 80.0);
+    assert_eq!(metrics["imu.yaw"], 90.0);
+    assert_eq!(metrics["imu.yaw_face"], 1.0);
+    assert_eq!(metrics["motor.C.position"], 360.0);
+    assert_eq!(metrics["motor.C.device_type"], 0x30 as f64);
+}
+
+#[test]
+fn effective_names() {
+    assert_eq!(
+        effective_program_name("program.py"),
+        ("program.py".to_string(), false)
+    );
+    let exact = "a".repeat(MAX_PROGRAM_NAME_LEN);
+    assert_eq!(effective_program_name(&exact), (exact.clone(), false));
+
+    // The 2 byte "é" would end at byte 32, so it's left out entirely
+    let name = format!("{}é.py", "a".repeat(30));
+    assert_eq!(effective_program_name(&name), ("a".repeat(30), true));
+}
+
+#[test]
+fn color_names() {
+    assert_eq!(Color::from_name("Azure"), Some(Color::Azure));
+    assert_eq!(Color::from_name("grey"), None);
+    assert_eq!(Color::Turquoise.to_string(), "turquoise");
+    assert_eq!(Color::from_name(&Color::Red.to_string()), Some(Color::Red));
+    assert_eq!(Color::Blue.to_rgb(), (0, 85, 191));
+}
+
+#[test]
+fn typed_readings() {
+    let force = DeviceMessage::DeviceForceSensor {
+        port: HubPort::B,
+        value: 7,
+        pressure: true,
+    };
+    assert_eq!(
+        force.as_force(),
+        Some(ForceReading {
+            port: HubPort::B,
+            value: 7,
+            pressure: true,
+        })
+    );
+    assert_eq!(force.as_motor(), None);
+
+    let distance = DeviceMessage::DeviceDistanceSensor {
+        port: HubPort::D,
+        distance: -1,
+    };
+    assert_eq!(distance.as_distance(), Some((HubPort::D, -1)));
+    assert_eq!(distance.as_color(), None);
+}
+
+#[test]
+fn imu_units() {
+    let imu = DeviceMessage::DeviceImuValues {
+        up_face: HubFace::Top,
+        yaw_face: HubFace::Top,
+        yaw: 900,
+        pitch: -150,
+        roll: 0,
+        accelerometer_x: 0,
+        accelerometer_y: 0,
+        accelerometer_z: 1000,
+        gyroscope_x: 0,
+        gyroscope_y: 0,
+        gyroscope_z: -455,
+    }
+    .as_imu()
+    .unwrap();
+    assert_eq!(imu.yaw_degrees(), 90.0);
+    assert_eq!(imu.pitch_degrees(), -15.0);
+    assert_eq!(imu.acceleration_g(), [0.0, 0.0, 1.0]);
+    assert_eq!(imu.gyro_dps(), [0.0, 0.0, -45.5]);
+
+    // Turning 90 degrees around z
+    let [w, x, y, z] = ImuReading { pitch: 0, ..imu }.to_quaternion();
+    let half = std::f32::consts::FRAC_1_SQRT_2;
+    assert!((w - half).abs() < 1e-6 && x.abs() < 1e-6 && y.abs() < 1e-6 && (z - half).abs() < 1e-6);
+}
+
+#[test]
+fn force_units() {
+    let force = DeviceMessage::DeviceForceSensor {
+        port: HubPort::A,
+        value: 255,
+        pressure: true,
+    }
+    .as_force()
+    .unwrap();
+    assert_eq!(force.newtons(), 10.0);
+    assert!(force.is_pressed());
+
+    let released = ForceReading {
+        value: 0,
+        pressure: false,
+        ..force
+    };
+    assert_eq!(released.newtons(), 0.0);
+    assert!(!released.is_pressed());
+}
+
+#[test]
+fn color_matrix_pixels() {
+    let mut pixels = [0; 9];
+    pixels[0] = 0xa9;
+    pixels[4] = 0x56;
+    pixels[8] = 0x1f;
+    let matrix = DeviceMessage::Device3x3ColorMatrix {
+        port: HubPort::C,
+        pixels,
+    }
+    .as_color_matrix()
+    .unwrap();
+    assert_eq!(
+        matrix[0][0],
+        ColorMatrixPixel {
+            color: Some(Color::Red),
+            brightness: 10
+        }
+    );
+    assert_eq!(matrix[1][1].color, Some(Color::Green));
+    assert_eq!(matrix[1][1].brightness, 5);
+    assert_eq!(matrix[2][2].color, None);
+    assert_eq!(matrix[0][1], ColorMatrixPixel::from(0));
+}
+
+#[test]
+fn motor_units() {
+    let motor = DeviceMessage::DeviceMotor {
+        port: HubPort::A,
+        motor_device_type: MotorDeviceType::Medium,
+        absolute_position: -90,
+        power: 50,
+        speed: -25,
+        position: 720,
+    }
+    .as_motor()
+    .unwrap();
+    assert_eq!(motor.degrees(), 720);
+    assert_eq!(motor.absolute_degrees(), -90);
+    assert_eq!(motor.power_percent(), 50);
+    assert_eq!(motor.speed_percent(), -25);
+}
+
+#[test]
+fn response_matching() {
+    let message = TxMessage::ClearSlotResponse(ClearSlotResponse::default());
+    assert!(GetHubNameResponse::from_message(message.clone()).is_err());
+    assert_eq!(
+        ClearSlotResponse::from_message(message),
+        Ok(ClearSlotResponse::default())
+    );
+}
+
+#[test]
+fn message_names() {
+    let message = TxMessage::ClearSlotResponse(ClearSlotResponse::default());
+    assert_eq!(message.name(), ClearSlotResponse::NAME);
+    assert_eq!(GetHubNameResponse::NAME, "GetHubNameResponse");
+}
+
+#[test]
+fn unknown_messages() {
+    let message = TxMessage::deserialize(vec![0x99, 1, 2]).unwrap();
+    assert_eq!(
+        message,
+        TxMessage::Unknown {
+            id: 0x99,
+            bytes: vec![1, 2]
+        }
+    );
+    assert_eq!(message.name(), "Unknown");
+
+    // The known devices before an unknown one are kept
+    let notification =
+        TxMessage::deserialize(vec![0x3c, 0x06, 0x00, 0x00, 80, 0x7f, 1, 2, 3]).unwrap();
+    assert_eq!(
+        notification,
+        TxMessage::DeviceNotification(DeviceNotification {
+            payload: vec![
+                DeviceMessage::DeviceBattery(80),
+                DeviceMessage::Unknown {
+                    id: 0x7f,
+                    data: vec![1, 2, 3]
+                },
+            ]
+        })
+    );
+
+    // The notification has to be as long as its size says
+    assert!(matches!(
+        TxMessage::deserialize(vec![0x3c, 0x02, 0x00, 0x00]),
+        Err(Error::TruncatedMessage)
+    ));
+}
+
+#[test]
+fn deserialize_borrowed() {
+    let data = [0x19, b'h', b'u', b'b', 0];
+    assert_eq!(
+        TxMessage::deserialize_slice(&data).unwrap(),
+        TxMessage::deserialize(data.to_vec()).unwrap()
+    );
+    assert!(matches!(
+        TxMessage::deserialize_slice(&data[..3]),
+        Err(Error::TruncatedMessage)
+    ));
+}
+
+#[test]
+fn device_types() {
+    assert_eq!(DeviceType::from(0), DeviceType::SpikePrime);
+    assert_eq!(DeviceType::from(1), DeviceType::SpikeEssential);
+    assert_eq!(DeviceType::from(0x1234), DeviceType::Unknown(0x1234));
+    assert_eq!(u16::from(DeviceType::Unknown(0x1234)), 0x1234);
+
+    let mut info = vec![0x01, 1, 0, 0, 0, 3, 0, 0, 0, 20, 0, 0, 2, 0, 1];
+    info.extend_from_slice(&[0x01, 0x00]);
+    let TxMessage::InfoResponse(info) = TxMessage::deserialize(info).unwrap() else {
+        panic!("expected an InfoResponse");
+    };
+    assert_eq!(info.product_group_device_type, DeviceType::SpikeEssential);
+}
+
+#[test]
+fn port_and_face_indices() {
+    for (i, port) in HubPort::all().into_iter().enumerate() {
+        assert_eq!(port.index(), i as u8);
+        assert_eq!(HubPort::try_from(port.index()).unwrap(), port);
+    }
+    for face in HubFace::all() {
+        assert_eq!(HubFace::try_from(face.index()).unwrap(), face);
+    }
+}
+
+#[test]
+fn console_lines() {
+    let mut reader = ConsoleReader::new();
+    for chunk in ["hel", "lo\r", "\nwor", "ld\n\npar", "tial"] {
+        reader.push(&ConsoleNotification {
+            console_message: chunk.to_string(),
+        });
+    }
+    assert_eq!(reader.next_line().as_deref(), Some("hello"));
+    assert_eq!(reader.next_line().as_deref(), Some("world"));
+    assert_eq!(reader.next_line().as_deref(), Some(""));
+    assert_eq!(reader.next_line(), None);
+    assert_eq!(reader.flush().as_deref(), Some("partial"));
+    assert_eq!(reader.flush(), None);
+}
+
+#[test]
+fn owned_messages() {
+    let messages = [
+        RxMessage::StartFileUploadRequest(StartFileUploadRequest {
+            file_name: "program.py",
+            program_slot: 4,
+            crc32: 0x12345678,
+        }),
+        RxMessage::TransferChunkRequest(TransferChunkRequest {
+            crc32: 1,
+            payload: &[1, 2, 3],
+        }),
+        RxMessage::SetHubNameRequest(SetHubNameRequest { name: "Hub" }),
+        RxMessage::ClearSlotRequest(ClearSlotRequest { program_slot: 2 }),
+        RxMessage::GetHubNameRequest,
+    ];
+    for message in messages {
+        let owned = OwnedRxMessage::from(message.clone());
+        assert_eq!(owned.as_message(), message);
+        assert_eq!(owned.serialize(), message.serialize());
+    }
+}
@@ -0,0 +1,608 @@
+use std::time::Duration;
+
+use spike_prime::connection::{
+    CancellationToken, ConnectionState, SpikeConnection, SpikeConnectionConfig, cobs, message::*,
+    transport::MockTransport,
+};
+use spike_prime::error::*;
+
+#[tokio::test]
+async fn get_hub_name() -> Result<()> {
+    let mock = MockTransport::new();
+    mock.expect(RxMessage::GetHubNameRequest, [b"\x19Hub\0".to_vec()]);
+    let mut connection = SpikeConnection::from_transport(mock.clone()).await?;
+
+    assert_eq!(connection.get_hub_name().await?, "Hub");
+    assert_eq!(mock.remaining(), 0);
+    assert_eq!(mock.written()[1], cobs::encode(&[0x18]));
+    Ok(())
+}
+
+#[tokio::test]
+async fn start_program() -> Result<()> {
+    let mock = MockTransport::new();
+    let request = ProgramFlowRequest {
+        program_action: ProgramAction::Start,
+        program_slot: 3,
+    };
+    mock.expect(request.clone(), [vec![0x1f, 0x00]]);
+    mock.expect(request, [vec![0x1f, 0x01]]);
+    let mut connection = SpikeConnection::from_transport(mock.clone()).await?;
+
+    connection.start_program(3).await?;
+    assert!(matches!(
+        connection.start_program(3).await,
+        Err(Error::NotAcknowledged(..))
+    ));
+    assert_eq!(mock.remaining(), 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn upload_program() -> Result<()> {
+    let code = "print('hi')";
+    let crc32 = spike_prime::connection::padded_crc32(code.as_bytes());
+    let mock = MockTransport::new();
+    mock.expect(
+        StartFileUploadRequest {
+            file_name: "program.py",
+            program_slot: 0,
+            crc32,
+        },
+        [vec![0x0d, 0x00]],
+    );
+    mock.expect(
+        TransferChunkRequest {
+            crc32,
+            payload: code.as_bytes(),
+        },
+        [vec![0x11, 0x00]],
+    );
+    let mut connection = SpikeConnection::from_transport(mock.clone()).await?;
+
+    let report = connection
+        .upload_program(0, "program.py".to_string(), code.to_string())
+        .await?;
+    assert_eq!(report.chunks, 1);
+    assert_eq!(mock.remaining(), 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn chunk_iter() -> Result<()> {
+    let connection = SpikeConnection::from_transport(MockTransport::new()).await?;
+    let data = vec![7; 1100];
+
+    let chunks: Vec<_> = connection.chunk_iter(&data).collect();
+    let lens: Vec<_> = chunks.iter().map(|chunk| chunk.payload.len()).collect();
+    assert_eq!(lens, [512, 512, 76]);
+    assert_eq!(
+        chunks.last().unwrap().crc32,
+        spike_prime::connection::padded_crc32(&data)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn upload_cancelled() -> Result<()> {
+    let code = "print('hi')";
+    let mock = MockTransport::new();
+    mock.expect(
+        StartFileUploadRequest {
+            file_name: "program.py",
+            program_slot: 3,
+            crc32: spike_prime::connection::padded_crc32(code.as_bytes()),
+        },
+        [vec![0x0d, 0x00]],
+    );
+    mock.expect(ClearSlotRequest { program_slot: 3 }, [vec![0x47, 0x00]]);
+    let mut connection = SpikeConnection::from_transport(mock.clone()).await?;
+
+    let cancel = CancellationToken::new();
+    cancel.cancel();
+    let result = connection
+        .upload_program_cancellable(3, "program.py".to_string(), code.to_string(), cancel)
+        .await;
+    assert!(matches!(result, Err(Error::Cancelled)));
+    assert_eq!(mock.remaining(), 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn upload_not_acknowledged() -> Result<()> {
+    let mock = MockTransport::new();
+    mock.expect(
+        StartFileUploadRequest {
+            file_name: "program.py",
+            program_slot: 0,
+            crc32: spike_prime::connection::padded_crc32(b""),
+        },
+        [vec![0x0d, 0x01]],
+    );
+    let mut connection = SpikeConnection::from_transport(mock).await?;
+
+    assert!(matches!(
+        connection
+            .upload_program(0, "program.py".to_string(), String::new())
+            .await,
+        Err(Error::NotAcknowledged("StartFileUploadRequest", None))
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn unsolicited_notifications() -> Result<()> {
+    let mock = MockTransport::new();
+    let mut connection = SpikeConnection::from_transport(mock.clone()).await?;
+
+    mock.send(b"\x21hello\n\0");
+    assert_eq!(
+        connection.console_notification().await.console_message,
+        "hello\n"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn await_next_program_stop() -> Result<()> {
+    let mock = MockTransport::new();
+    let mut connection = SpikeConnection::from_transport(mock.clone()).await?;
+
+    mock.send(&[0x20, 0x00]);
+    mock.send(&[0x20, 0x01]);
+    connection.await_next_program_stop().await?;
+    assert!(connection.try_program_flow_notification().is_none());
+
+    mock.close();
+    assert!(matches!(
+        connection.await_next_program_stop().await,
+        Err(Error::Disconnected)
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn connection_state() -> Result<()> {
+    let mock = MockTransport::new();
+    let mut connection = SpikeConnection::from_transport(mock.clone()).await?;
+    assert!(connection.is_connected());
+
+    mock.close();
+    assert!(matches!(
+        connection.receive_message().await,
+        Err(Error::Disconnected)
+    ));
+    assert!(!connection.is_connected());
+    assert_eq!(connection.connection_state(), ConnectionState::Disconnected);
+    Ok(())
+}
+
+#[tokio::test]
+async fn auto_reconnect() -> Result<()> {
+    let mock = MockTransport::new();
+    mock.expect(RxMessage::GetHubNameRequest, [b"\x19Hub\0".to_vec()]);
+    let mut connection = SpikeConnection::from_transport(mock.clone()).await?;
+    connection.set_auto_reconnect(true);
+
+    mock.close();
+    assert!(matches!(
+        connection.receive_message().await,
+        Err(Error::Disconnected)
+    ));
+    // The write fails, so the hub is relinked, and the response comes in on the new link
+    assert_eq!(connection.get_hub_name().await?, "Hub");
+    assert!(connection.is_connected());
+    assert_eq!(mock.remaining(), 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn deploy_and_run() -> Result<()> {
+    let code = "print('a')\r\nprint('b')\r\n";
+    let crc32 = spike_prime::connection::padded_crc32(code.as_bytes());
+    let mock = MockTransport::new();
+    // The slot was already empty
+    mock.expect(ClearSlotRequest { program_slot: 2 }, [vec![0x47, 0x01]]);
+    mock.expect(
+        StartFileUploadRequest {
+            file_name: "program.py",
+            program_slot: 2,
+            crc32,
+        },
+        [vec![0x0d, 0x00]],
+    );
+    mock.expect(
+        TransferChunkRequest {
+            crc32,
+            payload: code.as_bytes(),
+        },
+        [vec![0x11, 0x00]],
+    );
+    mock.expect(
+        ProgramFlowRequest {
+            program_action: ProgramAction::Start,
+            program_slot: 2,
+        },
+        [vec![0x1f, 0x01]],
+    );
+    let mut connection = SpikeConnection::from_transport(mock.clone()).await?;
+
+    let result = connection
+        .deploy_and_run(2, "program.py", "print('a')\nprint('b')\r\n")
+        .await;
+    assert!(matches!(result, Err(Error::StartFailed { slot: 2, .. })));
+    assert_eq!(mock.remaining(), 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn upload_line_endings() -> Result<()> {
+    let crlf = "a\r\nb";
+    let crc32 = spike_prime::connection::padded_crc32(crlf.as_bytes());
+
+    let mut written = Vec::new();
+    for code in ["a\nb", "a\r\nb"] {
+        let mock = MockTransport::new();
+        mock.expect(
+            StartFileUploadRequest {
+                file_name: "program.py",
+                program_slot: 0,
+                crc32,
+            },
+            [vec![0x0d, 0x00]],
+        );
+        mock.expect(
+            TransferChunkRequest {
+                crc32,
+                payload: crlf.as_bytes(),
+            },
+            [vec![0x11, 0x00]],
+        );
+        let mut connection = SpikeConnection::from_transport(mock.clone()).await?;
+
+        let report = connection
+            .upload_program(0, "program.py".to_string(), code.to_string())
+            .await?;
+        assert_eq!(report.crc32, crc32);
+        written.push(mock.written());
+    }
+    assert_eq!(written[0], written[1]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn wait_for_device() -> Result<()> {
+    let mock = MockTransport::new();
+    mock.expect(
+        DeviceNotificationRequest { interval: 10 },
+        [vec![0x29, 0x00]],
+    );
+    let mut connection = SpikeConnection::from_transport(mock.clone()).await?;
+
+    // A force sensor on port B
+    mock.send(&[0x3c, 0x04, 0x00, 0x0b, 0x01, 0x80, 0x00]);
+    let device = connection
+        .wait_for_device(HubPort::B, Duration::from_secs(1))
+        .await?;
+    assert_eq!(device.as_force().unwrap().value, 0x80);
+    assert!(matches!(
+        connection
+            .wait_for_device(HubPort::A, Duration::from_millis(50))
+            .await,
+        Err(Error::Timeout)
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn console_burst() -> Result<()> {
+    let mock = MockTransport::new();
+    mock.expect(RxMessage::GetHubNameRequest, [b"\x19Hub\0".to_vec()]);
+    let config = SpikeConnectionConfig {
+        console_capacity: 100,
+        ..Default::default()
+    };
+    let mut connection = SpikeConnection::from_transport_with_config(mock.clone(), config).await?;
+
+    // The console output isn't read, but fits in its buffer, so it doesn't hold up the response
+    for _ in 0..100 {
+        mock.send(b"\x21spam\n\0");
+    }
+    let name = tokio::time::timeout(Duration::from_secs(1), connection.get_hub_name()).await;
+    assert_eq!(name.expect("response was held up")?, "Hub");
+    Ok(())
+}
+
+#[tokio::test]
+async fn unread_console() -> Result<()> {
+    let mock = MockTransport::new();
+    mock.expect(RxMessage::GetHubNameRequest, [b"\x19Hub\0".to_vec()]);
+    let mut connection = SpikeConnection::from_transport(mock.clone()).await?;
+
+    // More console output than fits in its buffer is dropped, instead of holding up the response
+    let capacity = SpikeConnectionConfig::default().console_capacity;
+    for _ in 0..capacity + 10 {
+        mock.send(b"\x21spam\n\0");
+    }
+    let name = tokio::time::timeout(Duration::from_secs(1), connection.get_hub_name()).await;
+    assert_eq!(name.expect("response was held up")?, "Hub");
+    assert_eq!(connection.dropped_console_count(), 10);
+    Ok(())
+}
+
+#[tokio::test]
+async fn unread_streams() -> Result<()> {
+    let mock = MockTransport::new();
+    mock.expect(RxMessage::GetHubNameRequest, [b"\x19Hub\0".to_vec()]);
+    let mut connection = SpikeConnection::from_transport(mock.clone()).await?;
+    let _devices = connection.device_notification_stream(1).await;
+    let _events = connection.program_event_stream().await;
+
+    // Neither stream is read, but that mustn't hold up the response
+    let capacity = SpikeConnectionConfig::default().console_capacity;
+    for _ in 0..capacity + 10 {
+        mock.send(b"\x21spam\n\0");
+        mock.send(&[0x3c, 0x02, 0x00, 0x00, 80]);
+    }
+    let name = tokio::time::timeout(Duration::from_secs(1), connection.get_hub_name()).await;
+    assert_eq!(name.expect("response was held up")?, "Hub");
+    assert_eq!(connection.dropped_console_count(), 10);
+    assert_eq!(
+        connection
+            .device_notification()
+            .await
+            .and_then(|n| n.battery()),
+        Some(80)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn ignore_unknown() -> Result<()> {
+    let mock = MockTransport::new();
+    mock.expect(RxMessage::GetHubNameRequest, [b"\x19Hub\0".to_vec()]);
+    let mut connection = SpikeConnection::from_transport(mock.clone()).await?;
+    connection.set_ignore_unknown(true);
+
+    mock.send(&[0x99, 1, 2]);
+    // A notification with an unknown device in it isn't an unknown message
+    mock.send(&[0x3c, 0x04, 0x00, 0x00, 80, 0x7f, 1]);
+    // Wait for the messages before to be received
+    assert_eq!(connection.get_hub_name().await?, "Hub");
+
+    assert_eq!(connection.unknown_message_count(), 1);
+    assert!(connection.try_decode_error().is_none());
+    let notification = connection.device_notification().await.unwrap();
+    assert_eq!(notification.battery(), Some(80));
+    assert_eq!(
+        notification.payload[1],
+        DeviceMessage::Unknown {
+            id: 0x7f,
+            data: vec![1]
+        }
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn stop_all_programs() -> Result<()> {
+    let mock = MockTransport::new();
+    // Nothing was started, so slot 0 is stopped, and not being acknowledged means nothing was running
+    mock.expect(
+        ProgramFlowRequest {
+            program_action: ProgramAction::Stop,
+            program_slot: 0,
+        },
+        [vec![0x1f, 0x01]],
+    );
+    mock.expect(
+        ProgramFlowRequest {
+            program_action: ProgramAction::Start,
+            program_slot: 5,
+        },
+        [vec![0x1f, 0x00]],
+    );
+    mock.expect(
+        ProgramFlowRequest {
+            program_action: ProgramAction::Stop,
+            program_slot: 5,
+        },
+        [vec![0x1f, 0x00]],
+    );
+    let mut connection = SpikeConnection::from_transport(mock.clone()).await?;
+
+    connection.stop_all_programs().await?;
+    connection.start_program(5).await?;
+    connection.stop_all_programs().await?;
+    assert_eq!(mock.remaining(), 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn upload_file() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("spike_prime_upload_file_{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("main.py");
+    std::fs::write(&path, "print('hi')\n")?;
+
+    let code = "print('hi')\r\n";
+    let crc32 = spike_prime::connection::padded_crc32(code.as_bytes());
+    let mock = MockTransport::new();
+    mock.expect(
+        StartFileUploadRequest {
+            file_name: "main.py",
+            program_slot: 1,
+            crc32,
+        },
+        [vec![0x0d, 0x00]],
+    );
+    mock.expect(
+        TransferChunkRequest {
+            crc32,
+            payload: code.as_bytes(),
+        },
+        [vec![0x11, 0x00]],
+    );
+    let mut connection = SpikeConnection::from_transport(mock.clone()).await?;
+
+    let result = connection.upload_file(1, &path).await;
+    std::fs::remove_dir_all(&dir)?;
+    assert_eq!(result?.crc32, crc32);
+    assert_eq!(mock.remaining(), 0);
+    Ok(())
+}
+
+/// Expects a firmware update of `firmware` that the hub already has the first 700 bytes of, responding to each stage with its status.
+fn expect_firmware_update(firmware: &[u8], statuses: [u8; 3]) -> MockTransport {
+    use sha1::{Digest, Sha1};
+
+    let file_sha: [u8; 20] = Sha1::digest(firmware).into();
+    let crc32 = spike_prime::connection::padded_crc32(firmware);
+    let mock = MockTransport::new();
+    let mut start = vec![0x0b, statuses[0]];
+    start.extend_from_slice(&700u32.to_le_bytes());
+    mock.expect(StartFirmwareUploadRequest { file_sha, crc32 }, [start]);
+    if statuses[0] != 0 {
+        return mock;
+    }
+    // 700 is in the middle of the second chunk, so only the rest of it is sent
+    mock.expect(
+        TransferChunkRequest {
+            crc32: spike_prime::connection::padded_crc32(&firmware[..1024]),
+            payload: &firmware[700..1024],
+        },
+        [vec![0x11, statuses[1]]],
+    );
+    if statuses[1] != 0 {
+        return mock;
+    }
+    mock.expect(
+        TransferChunkRequest {
+            crc32,
+            payload: &firmware[1024..],
+        },
+        [vec![0x11, 0x00]],
+    );
+    mock.expect(
+        BeginFirmwareUpdateRequest { file_sha, crc32 },
+        [vec![0x15, statuses[2]]],
+    );
+    mock
+}
+
+#[tokio::test]
+async fn update_firmware_resumed() -> Result<()> {
+    let firmware: Vec<u8> = (0..1100).map(|i| i as u8).collect();
+    let mock = expect_firmware_update(&firmware, [0, 0, 0]);
+    let mut connection = SpikeConnection::from_transport(mock.clone()).await?;
+
+    connection.update_firmware(firmware).await?;
+    assert_eq!(mock.remaining(), 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn update_firmware_not_acknowledged() -> Result<()> {
+    let firmware: Vec<u8> = (0..1100).map(|i| i as u8).collect();
+    let stages = [
+        ([1, 0, 0], "StartFirmwareUploadRequest", None),
+        ([0, 1, 0], "TransferChunkRequest", Some(700)),
+        ([0, 0, 1], "BeginFirmwareUpdateRequest", None),
+    ];
+    for (statuses, request, position) in stages {
+        let mock = expect_firmware_update(&firmware, statuses);
+        let mut connection = SpikeConnection::from_transport(mock.clone()).await?;
+
+        let result = connection.update_firmware(firmware.clone()).await;
+        assert!(
+            matches!(result, Err(Error::NotAcknowledged(r, p)) if r == request && p == position),
+            "{result:?}"
+        );
+        assert_eq!(mock.remaining(), 0);
+    }
+    Ok(())
+}
+
+/// Expects `code` to be uploaded to slot 0 as `name`, in a single chunk.
+fn expect_upload(mock: &MockTransport, name: &str, code: &str) {
+    let crc32 = spike_prime::connection::padded_crc32(code.as_bytes());
+    mock.expect(
+        StartFileUploadRequest {
+            file_name: name,
+            program_slot: 0,
+            crc32,
+        },
+        [vec![0x0d, 0x00]],
+    );
+    mock.expect(
+        TransferChunkRequest {
+            crc32,
+            payload: code.as_bytes(),
+        },
+        [vec![0x11, 0x00]],
+    );
+}
+
+#[tokio::test]
+async fn upload_if_changed() -> Result<()> {
+    let mock = MockTransport::new();
+    expect_upload(&mock, "a.py", "print(1)");
+    expect_upload(&mock, "a.py", "print(2)");
+    expect_upload(&mock, "b.py", "print(2)");
+    expect_upload(&mock, "a.py", "print(2)");
+    let mut connection = SpikeConnection::from_transport(mock.clone()).await?;
+    let mut upload = async |name: &str, code: &str| {
+        connection
+            .upload_if_changed(0, name.to_string(), code.to_string())
+            .await
+    };
+
+    assert!(upload("a.py", "print(1)").await?);
+    // Nothing changed
+    assert!(!upload("a.py", "print(1)").await?);
+    assert!(upload("a.py", "print(2)").await?);
+    // b.py replaces a.py in the slot, so a.py has to be uploaded again
+    assert!(upload("b.py", "print(2)").await?);
+    assert!(upload("a.py", "print(2)").await?);
+    assert_eq!(mock.remaining(), 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn upload_if_changed_after_reconnect() -> Result<()> {
+    let mock = MockTransport::new();
+    expect_upload(&mock, "a.py", "print(1)");
+    mock.expect_connect();
+    expect_upload(&mock, "a.py", "print(1)");
+    let mut connection = SpikeConnection::from_transport(mock.clone()).await?;
+
+    assert!(
+        connection
+            .upload_if_changed(0, "a.py".to_string(), "print(1)".to_string())
+            .await?
+    );
+    connection.reconnect().await?;
+    // The hub could have been rebooted, so the program is uploaded again
+    assert!(
+        connection
+            .upload_if_changed(0, "a.py".to_string(), "print(1)".to_string())
+            .await?
+    );
+    assert_eq!(mock.remaining(), 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn deploy_clear_failed() -> Result<()> {
+    let mock = MockTransport::new();
+    mock.expect(ClearSlotRequest { program_slot: 2 }, []);
+    let mut connection = SpikeConnection::from_transport(mock.clone()).await?;
+    mock.close();
+
+    let result = connection.deploy_and_run(2, "program.py", "").await;
+    assert!(
+        matches!(&result, Err(Error::ClearFailed { slot: 2, error }) if matches!(**error, Error::Disconnected)),
+        "{result:?}"
+    );
+    Ok(())
+}